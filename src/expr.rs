@@ -0,0 +1,388 @@
+//! Evaluation of the integer constant expressions used by `#if` and `#elif`.
+
+use std::collections::{HashMap, HashSet};
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(&'static str),
+    /// `__has_include("name")` or `__has_include(<name>)`, with whether the name is quoted.
+    HasInclude(String, bool),
+}
+
+/// Operators recognized by the tokenizer, longest first so `<<` wins over `<`.
+const OPERATORS: [&str; 24] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!", "~", "&",
+    "|", "^", "?", ":", "(", ")",
+];
+
+/// Evaluates a `#if` expression against the object-like macros in `macros`.
+///
+/// `defined(NAME)` and `defined NAME` are replaced by 1 or 0, then macro names are
+/// substituted by their values before the arithmetic, comparison, bitwise, logical and `?:`
+/// operators are evaluated with C precedence. Any identifier left after substitution counts
+/// as 0, except `true`, which counts as 1. Integer literals may be hex, octal or binary and
+/// carry `U`/`L` suffixes, and character constants count as their code. `__has_include("name")` and
+/// `__has_include(<name>)` are 1 if `has_include` says the header can be found, given its
+/// name and whether it is quoted. The name of every macro tested with `defined` or
+/// substituted is added to `used`.
+pub fn evaluate(
+    expr: &str,
+    macros: &HashMap<String, String>,
+    has_include: &dyn Fn(&str, bool) -> bool,
+    used: &mut HashSet<String>,
+) -> Result<i64, String> {
+    let tokens = substitute(&tokenize(expr)?, macros, has_include, used, &mut HashSet::new())?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let value = parser.conditional(true)?;
+    match parser.tokens.get(parser.pos) {
+        None => Ok(value),
+        Some(token) => Err(format!("unexpected {} in #if expression", describe(token))),
+    }
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+
+    while let Some(c) = rest.chars().next() {
+        if c.is_ascii_digit() {
+            let end = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+            let value = integer_value(&rest[..end])
+                .ok_or_else(|| format!("invalid number '{}' in #if expression", &rest[..end]))?;
+            tokens.push(Token::Number(value));
+            rest = &rest[end..];
+        } else if c == '\'' {
+            let mut chars = rest[1..].chars().peekable();
+            let value = match chars.next() {
+                Some('\\') => escape_value(&mut chars),
+                Some(c) if c != '\'' => Some(c as u32),
+                _ => None,
+            };
+            let (Some(value), Some('\'')) = (value, chars.next()) else {
+                return Err("invalid character constant in #if expression".into());
+            };
+            tokens.push(Token::Number(value as i64));
+            rest = &rest[rest.len() - chars.map(char::len_utf8).sum::<usize>()..];
+        } else if c.is_alphabetic() || c == '_' {
+            let end = rest.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(rest.len());
+            if &rest[..end] == "__has_include" {
+                let (token, len) = has_include_operand(&rest[end..])?;
+                tokens.push(token);
+                rest = &rest[end + len..];
+            } else {
+                tokens.push(Token::Ident(rest[..end].to_string()));
+                rest = &rest[end..];
+            }
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            return Err(format!("unexpected character '{}' in #if expression", c));
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+/// The value of the C integer literal `literal`, such as `42`, `0x1F`, `017`, `0b101` or
+/// `10UL`. Values too large for an `i64` wrap around, as converting from `unsigned long long`
+/// would.
+fn integer_value(literal: &str) -> Option<i64> {
+    let digits = literal.trim_end_matches(['u', 'U', 'l', 'L']);
+    let prefixed = |lower: &str, upper: &str| digits.strip_prefix(lower).or_else(|| digits.strip_prefix(upper));
+    let (radix, digits) = if let Some(hex) = prefixed("0x", "0X") {
+        (16, hex)
+    } else if let Some(binary) = prefixed("0b", "0B") {
+        (2, binary)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (8, &digits[1..])
+    } else {
+        (10, digits)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    u64::from_str_radix(digits, radix).ok().map(|value| value as i64)
+}
+
+/// Reads the rest of a C escape sequence from `chars`, just after its backslash, and returns
+/// the code it stands for, or `None` if it isn't a valid escape.
+pub fn escape_value(chars: &mut Peekable<Chars>) -> Option<u32> {
+    Some(match chars.next()? {
+        'n' => 0x0a,
+        't' => 0x09,
+        'r' => 0x0d,
+        'a' => 0x07,
+        'b' => 0x08,
+        'f' => 0x0c,
+        'v' => 0x0b,
+        c @ ('\\' | '"' | '\'' | '?') => c as u32,
+        c @ ('x' | '0'..='7') => {
+            let (radix, max_digits) = if c == 'x' { (16, usize::MAX) } else { (8, 2) };
+            let mut code = if c == 'x' { 0 } else { c.to_digit(8).unwrap() };
+            let mut digits = 0;
+            while digits < max_digits {
+                let Some(digit) = chars.peek().and_then(|c| c.to_digit(radix)) else {
+                    break;
+                };
+                code = code.checked_mul(radix)? + digit;
+                chars.next();
+                digits += 1;
+            }
+            if c == 'x' && digits == 0 {
+                return None;
+            }
+            code
+        }
+        _ => return None,
+    })
+}
+
+/// Reads the parenthesized header name following `__has_include` at the start of `rest`,
+/// returning it as a token along with the number of bytes it took up.
+fn has_include_operand(rest: &str) -> Result<(Token, usize), String> {
+    let invalid =
+        || String::from("expected '__has_include(\"FILE\")' or '__has_include(<FILE>)' in #if expression");
+    let inner = rest.trim_start().strip_prefix('(').ok_or_else(invalid)?.trim_start();
+    let (close, quoted) = match inner.chars().next() {
+        Some('"') => ('"', true),
+        Some('<') => ('>', false),
+        _ => return Err(invalid()),
+    };
+    let name_len = inner[1..].find(close).ok_or_else(invalid)?;
+    let after = inner[name_len + 2..].trim_start().strip_prefix(')').ok_or_else(invalid)?;
+    let token = Token::HasInclude(inner[1..name_len + 1].to_string(), quoted);
+    Ok((token, rest.len() - after.len()))
+}
+
+/// Resolves `defined(NAME)`, `defined NAME` and `__has_include`, and replaces macro names by
+/// their tokenized values. `expanding` holds the macros whose values are being substituted, so
+/// a self-referential macro fails instead of recursing forever.
+fn substitute(
+    tokens: &[Token],
+    macros: &HashMap<String, String>,
+    has_include: &dyn Fn(&str, bool) -> bool,
+    used: &mut HashSet<String>,
+    expanding: &mut HashSet<String>,
+) -> Result<Vec<Token>, String> {
+    let mut output = Vec::new();
+    let mut iter = tokens.iter();
+
+    while let Some(token) = iter.next() {
+        match token {
+            Token::Ident(name) if name == "defined" => {
+                let name = match iter.next() {
+                    Some(Token::Ident(name)) => Some(name),
+                    Some(Token::Op("(")) => match (iter.next(), iter.next()) {
+                        (Some(Token::Ident(name)), Some(Token::Op(")"))) => Some(name),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                let name = name.ok_or("expected 'defined(NAME)' or 'defined NAME' in #if expression")?;
+                if macros.contains_key(name) {
+                    used.insert(name.clone());
+                }
+                output.push(Token::Number(macros.contains_key(name) as i64));
+            }
+            Token::Ident(name) => {
+                let Some(value) = macros.get(name) else {
+                    output.push(Token::Number((name == "true") as i64));
+                    continue;
+                };
+                if !expanding.insert(name.clone()) {
+                    return Err(format!("macro '{}' refers to itself in #if expression", name));
+                }
+                used.insert(name.clone());
+                output.extend(substitute(&tokenize(value)?, macros, has_include, used, expanding)?);
+                expanding.remove(name);
+            }
+            Token::HasInclude(name, quoted) => output.push(Token::Number(has_include(name, *quoted) as i64)),
+            _ => output.push(token.clone()),
+        }
+    }
+
+    Ok(output)
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Number(value) => format!("number {}", value),
+        Token::Ident(name) => format!("identifier '{}'", name),
+        Token::Op(op) => format!("'{}'", op),
+        Token::HasInclude(name, true) => format!("'__has_include(\"{}\")'", name),
+        Token::HasInclude(name, false) => format!("'__has_include(<{}>)'", name),
+    }
+}
+
+/// Binding strength of each binary operator; higher binds tighter.
+fn precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | ">" | "<=" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        _ => return None,
+    })
+}
+
+/// Precedence-climbing parser that evaluates as it goes. The `active` flag is false inside
+/// operands that C would not evaluate (the right of a short-circuited `&&`/`||`, the unused
+/// arm of `?:`), so that e.g. a division by zero there is not an error.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(found)) if *found == op => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(token) => Err(format!("expected '{}' but found {} in #if expression", op, describe(token))),
+            None => Err(format!("expected '{}' at end of #if expression", op)),
+        }
+    }
+
+    fn conditional(&mut self, active: bool) -> Result<i64, String> {
+        let condition = self.binary(1, active)?;
+        if self.peek_op() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let if_true = self.conditional(active && condition != 0)?;
+        self.expect(":")?;
+        let if_false = self.conditional(active && condition == 0)?;
+        Ok(if condition != 0 { if_true } else { if_false })
+    }
+
+    fn binary(&mut self, min_precedence: u8, active: bool) -> Result<i64, String> {
+        let mut lhs = self.unary(active)?;
+
+        while let Some(op) = self.peek_op() {
+            let Some(prec) = precedence(op).filter(|prec| *prec >= min_precedence) else {
+                break;
+            };
+            self.pos += 1;
+            let rhs_active = match op {
+                "&&" => active && lhs != 0,
+                "||" => active && lhs == 0,
+                _ => active,
+            };
+            let rhs = self.binary(prec + 1, rhs_active)?;
+            lhs = apply(op, lhs, rhs, rhs_active)?;
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self, active: bool) -> Result<i64, String> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Number(value)) => Ok(value),
+            Some(Token::Op("(")) => {
+                let value = self.conditional(active)?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(Token::Op("!")) => Ok((self.unary(active)? == 0) as i64),
+            Some(Token::Op("~")) => Ok(!self.unary(active)?),
+            Some(Token::Op("-")) => Ok(self.unary(active)?.wrapping_neg()),
+            Some(Token::Op("+")) => self.unary(active),
+            Some(token) => Err(format!("unexpected {} in #if expression", describe(&token))),
+            None => Err("unexpected end of #if expression".into()),
+        }
+    }
+}
+
+fn apply(op: &str, lhs: i64, rhs: i64, active: bool) -> Result<i64, String> {
+    if matches!(op, "/" | "%") && rhs == 0 {
+        return if active { Err("division by zero in #if expression".into()) } else { Ok(0) };
+    }
+    Ok(match op {
+        "||" => (lhs != 0 || rhs != 0) as i64,
+        "&&" => (lhs != 0 && rhs != 0) as i64,
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "&" => lhs & rhs,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        "<" => (lhs < rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "<<" => lhs.wrapping_shl(rhs as u32),
+        ">>" => lhs.wrapping_shr(rhs as u32),
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" => lhs.wrapping_div(rhs),
+        "%" => lhs.wrapping_rem(rhs),
+        _ => unreachable!("not a binary operator: {}", op),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluates `expr` with the macros `defines`, as names and bodies, and no headers.
+    fn eval(expr: &str, defines: &[(&str, &str)]) -> Result<i64, String> {
+        let macros = defines.iter().map(|(name, body)| (name.to_string(), body.to_string())).collect();
+        evaluate(expr, &macros, &|_, _| false, &mut HashSet::new())
+    }
+
+    #[test]
+    fn arithmetic_comparison_and_defined() {
+        assert_eq!(eval("1+1==2", &[]), Ok(1));
+        assert_eq!(eval("VERSION >= 2", &[("VERSION", "3")]), Ok(1));
+        assert_eq!(eval("defined(FOO) && !defined(BAR)", &[("FOO", "")]), Ok(1));
+        assert_eq!(eval("defined(FOO) && !defined(BAR)", &[("FOO", ""), ("BAR", "")]), Ok(0));
+        assert_eq!(eval("(2 + 3) * 4 - 10 / 5 % 3", &[]), Ok(18));
+    }
+
+    #[test]
+    fn defined_without_parentheses() {
+        let defines = [("FOO", ""), ("BAR", "")];
+        assert_eq!(eval("defined FOO && defined BAR", &defines), Ok(1));
+        assert_eq!(eval("defined FOO && !defined BAZ", &defines), Ok(1));
+        assert_eq!(eval("!defined(FOO) || defined BAZ", &defines), Ok(0));
+    }
+
+    #[test]
+    fn undefined_identifiers_are_zero() {
+        assert_eq!(eval("NOT_SET", &[]), Ok(0));
+        assert_eq!(eval("NOT_SET + 2", &[]), Ok(2));
+        assert_eq!(eval("defined(NOT_SET)", &[]), Ok(0));
+        assert_eq!(eval("defined(SET)", &[("SET", "0")]), Ok(1));
+    }
+
+    #[test]
+    fn literals_with_prefixes_suffixes_and_quotes() {
+        assert_eq!(eval("'A' == 65", &[]), Ok(1));
+        assert_eq!(eval("'\\n' == 10 && '\\x41' == 65", &[]), Ok(1));
+        assert_eq!(eval("0x10 == 16 && 010 == 8 && 0b101 == 5", &[]), Ok(1));
+        assert_eq!(eval("1UL + 2ll + 3u == 6", &[]), Ok(1));
+    }
+}
@@ -0,0 +1,2924 @@
+//! A C preprocessor that inlines includes, keeps the live branches of conditionals, expands
+//! macros and orders each definition ahead of its uses, all in memory.
+
+use regex::Regex;
+use petgraph::graph::{DiGraph, NodeIndex};
+use petgraph::Direction;
+use petgraph::algo::toposort;
+use petgraph::dot::Dot;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+mod expr;
+mod macros;
+mod tokenizer;
+
+pub use macros::Macro;
+
+/// Settings that control the preprocessing pipeline.
+#[derive(Clone)]
+pub struct Options {
+    /// Directories searched for headers, in order, after the including file's directory.
+    pub include_dirs: Vec<PathBuf>,
+    /// System header directories, as given by `-isystem`, searched in order after
+    /// [`Options::include_dirs`]. Headers found in them are listed in [`Output::system_headers`].
+    pub system_include_dirs: Vec<PathBuf>,
+    /// A directory standing for the root of the target system, for cross-compiling: its
+    /// `usr/local/include` and `usr/include` are searched last, as system directories.
+    pub sysroot: Option<PathBuf>,
+    /// Remove `//` and `/* */` comments from every file before the other passes.
+    pub strip_comments: bool,
+    /// Macros defined before the source is read, in order, as they would be by `#define NAME
+    /// VALUE` lines, or undefined like by `#undef NAME` where the value is `None`. `NAME` may
+    /// carry a parameter list, as in `MAX(a, b)`. Undefining removes predefined macros too.
+    pub macros: Vec<(String, Option<String>)>,
+    /// Insert `#line N "file"` directives wherever the output stops following on from the
+    /// original line before it, so that compiler diagnostics point into the original files.
+    pub line_markers: bool,
+    /// Keep every line in its original order instead of ordering definitions ahead of their
+    /// uses.
+    pub no_reorder: bool,
+    /// Replace the directive lines and dead branches dropped by preprocessing with comments
+    /// saying what was removed, e.g. `// [pp] removed: #ifdef DEBUG`.
+    pub keep_directives: bool,
+    /// How deeply headers may include other headers; the source's own includes are at depth 1.
+    /// Defaults to 200.
+    pub max_include_depth: usize,
+    /// The line ending used throughout the output.
+    pub line_endings: LineEndings,
+    /// Describe the dependencies between definitions in [`Output::dependency_graph`].
+    pub dependency_graph: bool,
+    /// Drop every `#pragma` whose first words are one of these, e.g. `omp` to drop `#pragma
+    /// omp parallel for` while keeping `#pragma pack(1)`.
+    pub strip_pragmas: Vec<String>,
+    /// How much is reported on stderr along the way.
+    pub verbosity: Verbosity,
+    /// The language `#define` lines are translated into.
+    pub target: Target,
+    /// Where headers are read through, if they should be read from disk only once across
+    /// several runs.
+    pub header_cache: Option<Arc<HeaderCache>>,
+    /// Inline every header at most once, as if each had `#pragma once`, whether or not it has
+    /// a `#pragma once` or include guard.
+    pub include_once_all: bool,
+    /// Warn about each macro the source itself defines, outside its headers, that is never
+    /// expanded or tested by `#ifdef`, `#ifndef`, `#if` or `#elif`.
+    pub warn_unused_macros: bool,
+    /// The C standard whose `__STDC__` and `__STDC_VERSION__` are predefined, if any.
+    pub standard: Option<Standard>,
+    /// Start from an empty macro table, without `__FILE__`, `__LINE__` or any of the other
+    /// predefined macros, including those of [`Options::standard`], so that only
+    /// [`Options::macros`] and the source's own definitions apply and the names otherwise
+    /// pass through as written.
+    pub no_predefined: bool,
+    /// Macros whose every expansion is reported on stderr step by step, unless
+    /// [`Options::verbosity`] is [`Verbosity::Quiet`].
+    pub trace_macros: Vec<String>,
+    /// The directory the source's own quoted includes are looked up in first, instead of the
+    /// directory of its path; for a source with no file of its own, such as stdin.
+    pub base_dir: Option<PathBuf>,
+    /// Which angle-bracket includes are inlined rather than left for the compiler.
+    pub inline_system: InlineSystem,
+    /// How files that aren't valid UTF-8 are decoded.
+    pub encoding: Encoding,
+    /// What becomes of runs of blank lines in the output.
+    pub blank_lines: BlankLines,
+    /// Matches the signature of a function definition in place of [`FUNCTION_PATTERN`], for
+    /// codebases whose style that doesn't recognize. It must capture the same three groups
+    /// and end its match at the body's `{`; other matches aren't taken for definitions.
+    pub function_pattern: Option<Regex>,
+}
+
+/// How the bytes of a file are turned into text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Only UTF-8; a file that isn't valid UTF-8 is an error.
+    #[default]
+    Utf8,
+    /// UTF-8, or Latin-1 for a file that isn't valid UTF-8, so that any file can be read.
+    Latin1,
+}
+
+/// The contents of the headers read so far, shared between preprocessing runs (e.g. of every
+/// file in a batch, which may run in parallel) through [`Options::header_cache`], so that each
+/// header is read from disk only once.
+#[derive(Default)]
+pub struct HeaderCache {
+    /// File contents by canonical path.
+    files: Mutex<HashMap<PathBuf, Arc<str>>>,
+    reads: AtomicUsize,
+}
+
+impl HeaderCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many files have been read from disk through the cache.
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    /// The contents of the file at `path`, whose canonical path is `canonical`, read from disk
+    /// with `encoding` the first time.
+    fn read(&self, path: &Path, canonical: &Path, encoding: Encoding) -> Result<Arc<str>, PreprocessError> {
+        if let Some(code) = self.files.lock().unwrap().get(canonical) {
+            return Ok(code.clone());
+        }
+        let code: Arc<str> = read_text(path, encoding)?.into();
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.files.lock().unwrap().insert(canonical.to_path_buf(), code.clone());
+        Ok(code)
+    }
+}
+
+/// What becomes of `#define` lines in the output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Target {
+    /// Plain C: they are removed, like every other directive that has been applied.
+    #[default]
+    C,
+    /// Rust: each becomes a `const` holding the macro's value, or a `#[cfg(NAME)]` attribute
+    /// for a macro defined as nothing.
+    Rust,
+}
+
+/// Which `#include <...>` directives are replaced by the header they name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InlineSystem {
+    /// None: they are all left in place for the compiler, as a compiler's own preprocessor
+    /// would need them.
+    #[default]
+    Never,
+    /// All of them, from the include directories; a header that isn't there is an error.
+    Always,
+    /// Those whose header is in one of the include directories; the others are left in place.
+    Found,
+}
+
+/// A C standard, as selected by `--std`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Standard {
+    /// ISO/IEC 9899:1999.
+    C99,
+    /// ISO/IEC 9899:2011.
+    C11,
+    /// ISO/IEC 9899:2018, which only fixed defects in C11.
+    C17,
+}
+
+impl Standard {
+    /// The value of `__STDC_VERSION__` under this standard.
+    pub fn version(self) -> &'static str {
+        match self {
+            Standard::C99 => "199901L",
+            Standard::C11 => "201112L",
+            Standard::C17 => "201710L",
+        }
+    }
+}
+
+/// Which messages preprocessing prints to stderr; each level adds to the ones before it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Nothing; warnings are still counted in [`Output::warnings`].
+    Quiet,
+    /// Warnings.
+    #[default]
+    Normal,
+    /// Each header included or skipped, and each macro defined or undefined.
+    Verbose,
+    /// Where each definition is placed by reordering, and why.
+    Trace,
+}
+
+/// Whether the output keeps the blank lines it ends up with, including those left where
+/// directives and comments were removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlankLines {
+    /// Each run of blank lines becomes a single blank line.
+    Collapse,
+    /// Every blank line is kept.
+    #[default]
+    Preserve,
+}
+
+/// Which line ending the output uses. Every pass works on `\n`-terminated lines, and the
+/// chosen ending is applied to the finished output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEndings {
+    /// `\n` everywhere.
+    Lf,
+    /// `\r\n` everywhere.
+    Crlf,
+    /// Whichever of the two ends most lines of the source, `\n` when they are tied.
+    #[default]
+    Preserve,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            include_dirs: Vec::new(),
+            system_include_dirs: Vec::new(),
+            sysroot: None,
+            strip_comments: false,
+            macros: Vec::new(),
+            line_markers: false,
+            no_reorder: false,
+            keep_directives: false,
+            max_include_depth: 200,
+            line_endings: LineEndings::Preserve,
+            dependency_graph: false,
+            strip_pragmas: Vec::new(),
+            verbosity: Verbosity::Normal,
+            target: Target::C,
+            header_cache: None,
+            include_once_all: false,
+            warn_unused_macros: false,
+            standard: None,
+            no_predefined: false,
+            trace_macros: Vec::new(),
+            base_dir: None,
+            inline_system: InlineSystem::Never,
+            encoding: Encoding::Utf8,
+            blank_lines: BlankLines::Preserve,
+            function_pattern: None,
+        }
+    }
+}
+
+/// Why preprocessing failed. `location` fields give the `file:line` of the offending line,
+/// which the message displayed for the error starts with.
+#[derive(Debug)]
+pub enum PreprocessError {
+    /// A quoted `#include` names a header found in none of the searched paths.
+    IncludeNotFound { location: String, path: String, searched: Vec<String> },
+    /// A header includes itself, directly or through others. Holds the chain of files from
+    /// the header's first inclusion to the one including it again.
+    CircularInclude(Vec<String>),
+    /// An `#include` nested deeper than [`Options::max_include_depth`].
+    IncludeTooDeep { location: String, path: String, depth: usize },
+    /// An `#elif`, `#else` or `#endif` without an open block, an `#elif` or `#else` after the
+    /// block's `#else`, or a block never closed.
+    UnbalancedConditional { location: String, msg: String },
+    /// A directive missing its macro name, or an `#if` expression that can't be evaluated.
+    InvalidDirective { location: String, msg: String },
+    /// An `#error` directive in live code, with its message.
+    ErrorDirective { location: String, msg: String },
+    /// A macro that can't be expanded.
+    MacroError { location: String, msg: String },
+    /// A block comment still open at the end of a file, or a string literal at the end of a
+    /// line. `location` is where it begins, as `file:line:column`, and `what` says which it is.
+    Unterminated { location: String, what: &'static str },
+    /// A file can't be read, e.g. for lack of permission.
+    ReadFailed { path: String, error: std::io::Error },
+    /// A file isn't valid UTF-8 and [`Options::encoding`] doesn't allow another encoding.
+    /// `offset` is where the first byte that isn't part of a UTF-8 character is.
+    InvalidUtf8 { path: String, offset: usize },
+    /// Some other I/O operation failed.
+    Io(std::io::Error),
+}
+
+impl fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PreprocessError::IncludeNotFound { location, path, searched } => {
+                write!(f, "{}: header '{}' not found (searched: {})", location, path, searched.join(", "))
+            }
+            PreprocessError::CircularInclude(chain) => write!(f, "circular #include: {}", chain.join(" -> ")),
+            PreprocessError::IncludeTooDeep { location, path, depth } => {
+                write!(f, "{}: including '{}' exceeds the maximum include depth of {}", location, path, depth)
+            }
+            PreprocessError::UnbalancedConditional { location, msg }
+            | PreprocessError::InvalidDirective { location, msg }
+            | PreprocessError::MacroError { location, msg } => write!(f, "{}: {}", location, msg),
+            PreprocessError::ErrorDirective { location, msg } => write!(f, "{}: #error {}", location, msg),
+            PreprocessError::Unterminated { location, what } => write!(f, "{}: unterminated {}", location, what),
+            PreprocessError::ReadFailed { path, error } => write!(f, "cannot read '{}': {}", path, error),
+            PreprocessError::InvalidUtf8 { path, offset } => {
+                write!(f, "'{}' is not valid UTF-8: invalid byte at offset {}", path, offset)
+            }
+            PreprocessError::Io(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PreprocessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PreprocessError::ReadFailed { error, .. } | PreprocessError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PreprocessError {
+    fn from(error: std::io::Error) -> Self {
+        PreprocessError::Io(error)
+    }
+}
+
+/// The result of preprocessing one source file.
+pub struct Output {
+    /// The preprocessed code.
+    pub code: String,
+    /// The number of warnings reported along the way, e.g. by `#warning`.
+    pub warnings: usize,
+    /// The macros defined at the end of the source, after every `#define` and `#undef` and
+    /// the command-line [`Options::macros`], by name. Predefined macros aren't included.
+    pub macros: BTreeMap<String, Macro>,
+    /// The function definitions found in the live code, in source order.
+    pub functions: Vec<Function>,
+    /// The graph the definitions are ordered by, in Graphviz DOT format, if
+    /// [`Options::dependency_graph`] asked for it. Each node is labelled with the names an
+    /// element defines, and each edge points from a definition to an element that `calls` or
+    /// `uses` it.
+    pub dependency_graph: Option<String>,
+    /// Every header found for an `#include`, directly or through another header, as found on
+    /// the search path, once each in the order they were first included.
+    pub headers: Vec<PathBuf>,
+    /// Those of [`Output::headers`] found in a system directory, from
+    /// [`Options::system_include_dirs`] or under [`Options::sysroot`], in the same order.
+    pub system_headers: Vec<PathBuf>,
+    /// The headers inlined, conditionals resolved and functions moved, in the order the
+    /// pipeline did so.
+    pub transformations: Vec<Transformation>,
+    /// Counts and timings of the work done.
+    pub stats: Stats,
+}
+
+/// A change made to the code on its way through the pipeline, as listed in
+/// [`Output::transformations`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Transformation {
+    /// The `#include` at `location` was replaced by the header found at `path`.
+    Inlined { location: String, path: PathBuf },
+    /// The conditional directive at `location`, e.g. `#ifdef DEBUG`, was reached in live
+    /// code, and the branch it starts was kept if `taken` and dropped otherwise.
+    Conditional { location: String, directive: String, taken: bool },
+    /// The function `name` was moved from the 1-based position `from` among the functions
+    /// defined to `to`.
+    Reordered { name: String, from: usize, to: usize },
+}
+
+/// What preprocessing one source took.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    /// Lines of code once the headers were inlined.
+    pub lines: usize,
+    /// Headers inlined, counting a header once for every time it was inlined.
+    pub includes: usize,
+    /// Macro expansions done, including those inside other expansions.
+    pub expansions: usize,
+    /// The expansions counted in `expansions`, by the name of the macro expanded.
+    pub macro_expansions: BTreeMap<String, usize>,
+    /// Function definitions that reordering moved to a different position among the
+    /// functions, as listed in [`Output::transformations`]; none with [`Options::no_reorder`].
+    pub functions_reordered: usize,
+    /// How long each pass took, in the order they ran.
+    pub stages: Vec<(&'static str, Duration)>,
+}
+
+impl Stats {
+    /// Records that the pass `stage` ended now, having started at `start`, which becomes the
+    /// start of the next one.
+    fn lap(&mut self, stage: &'static str, start: &mut Instant) {
+        let now = Instant::now();
+        self.stages.push((stage, now - *start));
+        *start = now;
+    }
+}
+
+/// A function definition found in the source.
+pub struct Function {
+    pub name: String,
+    /// Everything before the name in the signature, e.g. `static const char *`, with runs of
+    /// whitespace collapsed.
+    pub return_type: String,
+    /// The parameter declarations, e.g. `int a` and `char **argv`; empty for `()` and `(void)`.
+    pub params: Vec<String>,
+    /// The file the signature is in.
+    pub file: String,
+    /// The 1-based line number of the signature.
+    pub line: usize,
+}
+
+/// Preprocesses `source` and returns the resulting code. Quoted includes are looked up
+/// relative to the current directory before `opts.include_dirs`; see [`preprocess_named`]
+/// for code read from a file.
+pub fn preprocess(source: &str, opts: &Options) -> Result<String, PreprocessError> {
+    preprocess_named(source, Path::new("<input>"), opts).map(|output| output.code)
+}
+
+/// Preprocesses `source` like [`preprocess`], but writes the code to `out` line by line as it
+/// is produced instead of returning it. Nothing more is written once an error occurs, but
+/// what was written already stays written.
+pub fn preprocess_to_writer<W: Write>(source: &str, opts: &Options, mut out: W) -> Result<(), PreprocessError> {
+    preprocess_into(source, Path::new("<input>"), opts, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Preprocesses `source` as if it had been read from `path`: quoted includes are looked up
+/// relative to its directory, and diagnostics and `__FILE__` name it. Only headers are read
+/// from the filesystem.
+pub fn preprocess_named(source: &str, path: &Path, opts: &Options) -> Result<Output, PreprocessError> {
+    let mut code = Vec::with_capacity(source.len());
+    let mut output = preprocess_into(source, path, opts, &mut code)?;
+    output.code = String::from_utf8(code).expect("preprocessed code is built from strings");
+    Ok(output)
+}
+
+/// The macros `#if` expressions see before any source is read, by name, as
+/// [`evaluate_conditionals`] takes them: the predefined macros and those of
+/// [`Options::standard`], unless [`Options::no_predefined`] is set, and the command-line
+/// [`Options::macros`].
+fn initial_defines(opts: &Options) -> HashMap<String, String> {
+    let mut defines = HashMap::new();
+    if !opts.no_predefined {
+        defines.extend(macros::predefined().into_iter().map(|(name, definition)| (name, definition.body)));
+    }
+    if let Some(standard) = opts.standard.filter(|_| !opts.no_predefined) {
+        defines.insert("__STDC__".to_string(), "1".to_string());
+        defines.insert("__STDC_VERSION__".to_string(), standard.version().to_string());
+    }
+    for (name, value) in &opts.macros {
+        let (name, params) = name.split_at(name.find('(').unwrap_or(name.len()));
+        match value {
+            Some(value) => defines.insert(name.to_string(), format!("{} {}", params, value).trim().to_string()),
+            None => defines.remove(name),
+        };
+    }
+    defines
+}
+
+/// Worker for [`preprocess_named`] and [`preprocess_to_writer`], which writes the code to
+/// `out` and returns everything else with an empty [`Output::code`].
+fn preprocess_into(
+    source: &str,
+    path: &Path,
+    opts: &Options,
+    out: &mut dyn Write,
+) -> Result<Output, PreprocessError> {
+    let mut warnings = 0;
+    let mut stats = Stats::default();
+    let mut start = Instant::now();
+
+    // Step 1: Splice continued lines and optionally remove comments
+    let original_code = prepare_source(source, path, opts)?;
+    stats.lap("prepare", &mut start);
+
+    // Step 2: Inline #include directives, searching the source's directory and the -I paths
+    let mut used = HashSet::new();
+    let Inlined { lines: inlined_lines, headers, system_headers, mut transformations, mut counter } =
+        inline_includes(&original_code, path, opts, &mut used, &mut warnings, &mut stats)?;
+    stats.lines = inlined_lines.len();
+    stats.lap("includes", &mut start);
+
+    // Step 3: Keep only the live branches of conditional blocks
+    let defines = initial_defines(opts);
+    let live_lines =
+        evaluate_conditionals(inlined_lines, defines, path, opts, &mut used, &mut warnings, &mut transformations)?;
+    stats.lap("conditionals", &mut start);
+
+    // Step 4: Expand macros, applying each `#define` and `#undef` from where it is in the source
+    let own_macros = if opts.warn_unused_macros {
+        let mut in_comment = false;
+        let masked: Vec<String> = live_lines.iter().map(|line| mask_code(&line.text, &mut in_comment)).collect();
+        own_macros(&live_lines, &masked, path)
+    } else {
+        Vec::new()
+    };
+    let (expanded_lines, mut macros) =
+        handle_macros(&live_lines, opts, &mut used, &mut warnings, &mut stats, &mut counter)?;
+    stats.lap("macros", &mut start);
+    macros.retain(|_, definition| definition.location != "<built-in>");
+
+    // Step 5: Find the top-level definitions and, unless asked not to, reorder them
+    let mut in_comment = false;
+    let masked: Vec<String> = expanded_lines.iter().map(|line| mask_code(&line.text, &mut in_comment)).collect();
+    let (elements, functions) = find_elements(&expanded_lines, &masked, opts, &mut warnings);
+    let graph = dependency_graph(&masked, &elements);
+    let dependency_graph = opts.dependency_graph.then(|| Dot::new(&graph).to_string());
+    let reordered_lines = if opts.no_reorder {
+        expanded_lines
+    } else {
+        let reordered_lines = reorder_elements(&expanded_lines, &elements, graph, opts, &mut transformations)?;
+        let moved = transformations.iter().filter(|change| matches!(change, Transformation::Reordered { .. }));
+        stats.functions_reordered = moved.count();
+        reordered_lines
+    };
+    stats.lap("reorder", &mut start);
+
+    // Step 6: Write each line out
+    let crlf = match opts.line_endings {
+        LineEndings::Lf => false,
+        LineEndings::Crlf => true,
+        LineEndings::Preserve => source.matches("\r\n").count() * 2 > source.matches('\n').count(),
+    };
+    let newline = if crlf { "\r\n" } else { "\n" };
+    write_lines(&reordered_lines, opts, out, newline)?;
+    stats.lap("output", &mut start);
+
+    // Step 7: Report the source's own macros that nothing used
+    for (name, location) in own_macros.iter().filter(|(name, _)| !used.contains(name)) {
+        log(opts, Verbosity::Normal, format_args!("{}: warning: macro '{}' is never used", location, name));
+        warnings += 1;
+    }
+
+    let macros = macros.into_iter().collect();
+    stats.expansions = stats.macro_expansions.values().sum();
+    Ok(Output {
+        code: String::new(),
+        warnings,
+        macros,
+        functions,
+        dependency_graph,
+        headers,
+        system_headers,
+        transformations,
+        stats,
+    })
+}
+
+/// Reads the header file `path` as a set of macros to preload into [`Options::macros`] for
+/// other sources, preprocessing it with `opts` but without reordering. Returns the macros
+/// its `#define` and `#undef` lines leave defined, in the form [`Options::macros`] takes.
+pub fn read_macro_file(path: &Path, opts: &Options) -> Result<Vec<(String, Option<String>)>, PreprocessError> {
+    let source = read_text(path, opts.encoding)?;
+    let opts = Options { no_reorder: true, dependency_graph: false, warn_unused_macros: false, ..opts.clone() };
+    let output = preprocess_named(&source, path, &opts)?;
+    let macros = output.macros.into_iter().filter(|(_, definition)| definition.location != "<command line>");
+    Ok(macros
+        .map(|(name, definition)| match definition.params {
+            Some(params) => (format!("{}({})", name, params.join(", ")), Some(definition.body)),
+            None => (name, Some(definition.body)),
+        })
+        .collect())
+}
+
+/// A line of code tagged with the file and line number it was read from, so that passes
+/// running after includes are inlined can still report and expand original positions.
+#[derive(Clone, Debug)]
+struct Line {
+    text: String,
+    file: Rc<str>,
+    /// 1-based line number within `file`.
+    number: usize,
+}
+
+impl Line {
+    /// `file:line`, as used to prefix diagnostics.
+    fn location(&self) -> String {
+        format!("{}:{}", self.file, self.number)
+    }
+
+    /// The comment that stands in for this line when [`Options::keep_directives`] is set and
+    /// preprocessing drops it.
+    fn removed(&self) -> Line {
+        let code = self.text.trim_start();
+        let indent = &self.text[..self.text.len() - code.len()];
+        Line { text: format!("{}{}{}", indent, REMOVED, code), ..self.clone() }
+    }
+
+    /// Whether this is a comment [`Line::removed`] made.
+    fn is_removed(&self) -> bool {
+        self.text.trim_start().starts_with(REMOVED)
+    }
+}
+
+/// What starts the comment standing in for a line dropped with [`Options::keep_directives`].
+const REMOVED: &str = "// [pp] removed: ";
+
+/// Reads the file `path` as text decoded with `encoding`, failing with an error naming the
+/// file if it can't be read or decoded.
+pub fn read_text(path: &Path, encoding: Encoding) -> Result<String, PreprocessError> {
+    let shown = path.display().to_string();
+    let bytes = fs::read(path).map_err(|error| PreprocessError::ReadFailed { path: shown, error })?;
+    decode_text(bytes, encoding, path)
+}
+
+/// Decodes `bytes`, read from `path`, with `encoding`, as [`read_text`] does, failing with an
+/// error naming `path` if they aren't valid text. This is how a source read from anywhere
+/// but a file, such as stdin, is decoded.
+pub fn decode_text(bytes: Vec<u8>, encoding: Encoding, path: &Path) -> Result<String, PreprocessError> {
+    let shown = || path.display().to_string();
+    match String::from_utf8(bytes) {
+        Ok(text) => Ok(text),
+        // Every byte is a Latin-1 character, at the code point of the same value
+        Err(error) if encoding == Encoding::Latin1 => {
+            Ok(error.as_bytes().iter().map(|&byte| char::from(byte)).collect())
+        }
+        Err(error) => {
+            Err(PreprocessError::InvalidUtf8 { path: shown(), offset: error.utf8_error().valid_up_to() })
+        }
+    }
+}
+
+/// Reads the header file `path`, whose canonical path is `canonical`, through
+/// [`Options::header_cache`] if there is one, and prepares it with [`prepare_source`].
+fn read_source(path: &Path, canonical: &Path, opts: &Options) -> Result<String, PreprocessError> {
+    let code = match &opts.header_cache {
+        Some(cache) => cache.read(path, canonical, opts.encoding)?,
+        None => read_text(path, opts.encoding)?.into(),
+    };
+    prepare_source(&code, path, opts)
+}
+
+/// Applies the passes that work on the raw text of `file`: a leading UTF-8 byte order mark is
+/// dropped, line continuations are always joined, the result is checked with
+/// [`check_terminated`], and comments are stripped if `opts` asks for it.
+fn prepare_source(code: &str, file: &Path, opts: &Options) -> Result<String, PreprocessError> {
+    let code = join_continuations(code.strip_prefix('\u{feff}').unwrap_or(code));
+    check_terminated(&code, file)?;
+    Ok(if opts.strip_comments { strip_comments(&code) } else { code })
+}
+
+/// Fails with [`PreprocessError::Unterminated`] if `code`, read from `file`, ends inside a
+/// block comment or leaves a string literal open at the end of a line. The free text of
+/// `#error` and `#warning` lines isn't checked for string literals, and neither are
+/// character literals, so that apostrophes in such text don't count. Nor is anything inside
+/// an `#if 0` block, which is often used to comment out text that isn't valid code.
+fn check_terminated(code: &str, file: &Path) -> Result<(), PreprocessError> {
+    let free_text_regex = regex(r"^\s*#\s*(error|warning)\b");
+    let if_zero_regex = regex(r"^\s*#\s*if\s+0\s*(?:$|//|/\*)");
+    let open_regex = regex(r"^\s*#\s*if");
+    let else_regex = regex(r"^\s*#\s*(?:else|elif)\b");
+    let endif_regex = regex(r"^\s*#\s*endif\b");
+    let location = |idx: usize, text: &str, start: usize| {
+        format!("{}:{}:{}", file.display(), idx + 1, text[..start].chars().count() + 1)
+    };
+    let mut comment_start = None;
+    // How many conditional blocks deep the scan is inside an `#if 0` block, counting it
+    let mut if_zero_depth: usize = 0;
+
+    for (idx, text) in code.lines().enumerate() {
+        if comment_start.is_none() {
+            if if_zero_depth == 0 && if_zero_regex.is_match(text) {
+                if_zero_depth = 1;
+            } else if if_zero_depth > 0 && open_regex.is_match(text) {
+                if_zero_depth += 1;
+            } else if endif_regex.is_match(text) || (if_zero_depth == 1 && else_regex.is_match(text)) {
+                if_zero_depth = if_zero_depth.saturating_sub(1);
+            }
+        }
+        let mut chars = text.char_indices().peekable();
+        let mut quote = None;
+        while let Some((pos, c)) = chars.next() {
+            if comment_start.is_some() {
+                if c == '*' && chars.next_if(|&(_, next)| next == '/').is_some() {
+                    comment_start = None;
+                }
+                continue;
+            }
+            match quote {
+                None if c == '/' && chars.next_if(|&(_, next)| next == '/').is_some() => break,
+                None if c == '/' && chars.next_if(|&(_, next)| next == '*').is_some() => {
+                    comment_start = Some(location(idx, text, pos));
+                }
+                None if c == '"' || c == '\'' => quote = Some((c, pos)),
+                Some(_) if c == '\\' => {
+                    chars.next();
+                }
+                Some((open, _)) if c == open => quote = None,
+                _ => {}
+            }
+        }
+        if let Some(('"', start)) = quote {
+            if if_zero_depth == 0 && !free_text_regex.is_match(text) {
+                let location = location(idx, text, start);
+                return Err(PreprocessError::Unterminated { location, what: "string literal" });
+            }
+        }
+    }
+
+    match comment_start {
+        Some(location) => Err(PreprocessError::Unterminated { location, what: "comment" }),
+        None => Ok(()),
+    }
+}
+
+/// Splices every line ending in a backslash onto the line after it, removing the backslash
+/// and the newline, so later passes see a multi-line macro definition as one line. Each
+/// spliced line is followed by an empty line for every continuation it absorbed, which keeps
+/// the line numbers of everything after it unchanged. `\r\n` endings are accepted, and a
+/// backslash on the very last line is simply dropped.
+fn join_continuations(code: &str) -> String {
+    let mut joined = String::with_capacity(code.len());
+    let mut pending_blank_lines = 0;
+
+    for line in code.lines() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match line.strip_suffix('\\') {
+            Some(continued) => {
+                joined.push_str(continued);
+                pending_blank_lines += 1;
+            }
+            None => {
+                joined.push_str(line);
+                joined.push('\n');
+                for _ in 0..pending_blank_lines {
+                    joined.push('\n');
+                }
+                pending_blank_lines = 0;
+            }
+        }
+    }
+    if pending_blank_lines > 0 {
+        joined.push('\n');
+    }
+
+    joined
+}
+
+/// Removes `//` line comments and `/* ... */` block comments from `code`. Comment markers
+/// inside string and character literals are left alone. A block comment is replaced by a
+/// space, keeping any newlines it contained so line numbers don't shift, and whitespace
+/// left trailing before a line comment is trimmed.
+fn strip_comments(code: &str) -> String {
+    let mut stripped = String::with_capacity(code.len());
+    let mut chars = code.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                stripped.push(c);
+                while let Some(next) = chars.next() {
+                    stripped.push(next);
+                    if next == '\\' {
+                        if let Some(escaped) = chars.next() {
+                            stripped.push(escaped);
+                        }
+                    } else if next == c || next == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                stripped.truncate(stripped.trim_end_matches([' ', '\t']).len());
+                while chars.next_if(|&next| next != '\n').is_some() {}
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                stripped.push(' ');
+                let mut prev = '\0';
+                for next in chars.by_ref() {
+                    if next == '\n' {
+                        stripped.push('\n');
+                    } else if prev == '*' && next == '/' {
+                        break;
+                    }
+                    prev = next;
+                }
+            }
+            _ => stripped.push(c),
+        }
+    }
+
+    stripped
+}
+
+/// Inlines #include directives by replacing them with the content of the referenced files.
+///
+/// Headers are expanded recursively so the result is fully flattened. A header containing
+/// `#pragma once` is inlined at most once, identified by its canonical path so symlinks and
+/// `./` prefixes count as the same file, as is every header with
+/// [`Options::include_once_all`]. `#pragma once` lines are dropped from the output, and
+/// headers wrapped in a classic include guard are inlined without its `#ifndef` and `#endif`
+/// and skipped while their guard macro is defined, whether by an earlier inclusion through
+/// any path, a `#define` or [`Options::macros`]. The guard's `#define` is kept, so the later
+/// passes define the macro like any other.
+/// Any other header is inlined again wherever it is included. A header that ends up
+/// including itself is reported as an error naming the whole include chain.
+///
+/// `file` is the path `code` was read from. Quoted includes resolve against its directory
+/// first and then against `opts.include_dirs`; a quoted header that can't be found is an
+/// error. Angle-bracket includes usually name system headers, so they are left in place as
+/// written unless [`Options::inline_system`] says otherwise; they only search
+/// `opts.include_dirs`. Either kind then searches the system directories,
+/// [`Options::system_include_dirs`] and those under [`Options::sysroot`].
+///
+/// An `#include` followed by anything other than a quoted or angle-bracket name, as in
+/// `#include HEADER`, is macro-expanded and must then produce one of those forms. The macros
+/// used are the command-line ones and those defined above the line in the text read so far.
+///
+/// Conditional blocks are followed as [`evaluate_conditionals`] will follow them, against the
+/// macros defined so far, so an `#include` or `#define` in a dead branch, such as one under
+/// `#ifdef NEVER`, has no effect: the header isn't looked for, let alone inlined. The
+/// conditional directives and dead branches themselves are left for that pass to remove.
+///
+/// `#include_next`, in either form, only searches the include directories after the one the
+/// file containing it was found in, or all of them for a file found elsewhere. One that
+/// finds nothing prints a warning to stderr, adds one to `warnings` and is left in place.
+///
+/// Also returns every header found, once each, in the order they were first included, and
+/// which of them were found in a system directory. The headers inlined and the macro
+/// expansions done are counted in `stats`.
+fn inline_includes(
+    code: &str,
+    file: &Path,
+    opts: &Options,
+    used: &mut HashSet<String>,
+    warnings: &mut usize,
+    stats: &mut Stats,
+) -> Result<Inlined, PreprocessError> {
+    // A source that isn't read from a file can't be included by its headers, so its path is
+    // only informative
+    let canonical = fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+    let mut context = IncludeContext {
+        include_regex: regex(r#"^\s*#\s*include(_next)?\s*(?:"([^"]+)"|<([^>]+)>)"#),
+        computed_include_regex: regex(r"^\s*#\s*include\s+([A-Za-z_].*)"),
+        pragma_once_regex: regex(r"^\s*#\s*pragma\s+once\s*$"),
+        define_regex: regex(DEFINE_PATTERN),
+        undef_regex: regex(r"^\s*#\s*undef\s+(\w+)"),
+        conditional_regex: regex(r"^\s*#\s*\w+\s*(.*)"),
+        macros: initial_macros(opts),
+        defines: initial_defines(opts),
+        used,
+        warnings,
+        stats,
+        counter: 0,
+        transformations: Vec::new(),
+        opts,
+        visited: HashSet::new(),
+        headers: Vec::new(),
+        system_headers: Vec::new(),
+        stack: vec![(canonical, file.display().to_string(), None)],
+    };
+    let mut lines = Vec::new();
+    context.expand(code, file, &[], &mut lines)?;
+    let IncludeContext { headers, system_headers, transformations, counter, .. } = context;
+    Ok(Inlined { lines, headers, system_headers, transformations, counter })
+}
+
+/// The code and headers [`inline_includes`] returns.
+struct Inlined {
+    lines: Vec<Line>,
+    /// Every header found, once each, in the order they were first included.
+    headers: Vec<PathBuf>,
+    /// The headers found in a system directory.
+    system_headers: Vec<PathBuf>,
+    /// An [`Transformation::Inlined`] for every header inlined.
+    transformations: Vec<Transformation>,
+    /// The value the next use of `__COUNTER__` expands to, after its uses in computed includes.
+    counter: usize,
+}
+
+/// State threaded through the recursive expansion done by [`inline_includes`].
+struct IncludeContext<'a> {
+    include_regex: Regex,
+    computed_include_regex: Regex,
+    pragma_once_regex: Regex,
+    define_regex: Regex,
+    undef_regex: Regex,
+    /// Captures what follows the name of a directive.
+    conditional_regex: Regex,
+    /// The macros defined so far, for expanding computed includes.
+    macros: HashMap<String, Macro>,
+    /// The same macros as `#if` expressions see them.
+    defines: HashMap<String, String>,
+    /// Where the names of the macros expanded in computed includes are added.
+    used: &'a mut HashSet<String>,
+    warnings: &'a mut usize,
+    stats: &'a mut Stats,
+    /// The value the next use of `__COUNTER__` in a computed include expands to.
+    counter: usize,
+    /// An [`Transformation::Inlined`] for every header inlined so far.
+    transformations: Vec<Transformation>,
+    opts: &'a Options,
+    /// Every header already inlined that is only to be inlined once, by canonical path.
+    visited: HashSet<PathBuf>,
+    /// Every header found so far, in order, whether or not it was inlined.
+    headers: Vec<PathBuf>,
+    /// The headers in `headers` that were found in a system directory.
+    system_headers: Vec<PathBuf>,
+    /// Files currently being expanded, outermost first, as (canonical path, display name,
+    /// index of the include directory it was found in).
+    stack: Vec<(PathBuf, String, Option<usize>)>,
+}
+
+impl IncludeContext<'_> {
+    /// Appends the lines of `code`, read from `file`, to `output` with its includes inlined.
+    /// The 0-based line indices in `skip` (an include guard's `#ifndef` and `#endif`) are left
+    /// out. A line inside a block comment is never a directive, even if it looks like one.
+    fn expand(
+        &mut self,
+        code: &str,
+        file: &Path,
+        skip: &[usize],
+        output: &mut Vec<Line>,
+    ) -> Result<(), PreprocessError> {
+        let file_name: Rc<str> = file.display().to_string().into();
+        let mut in_comment = false;
+        // The conditional blocks open in this file, which decide whether its directives apply
+        let mut blocks = Vec::new();
+
+        for (idx, text) in code.lines().enumerate() {
+            let line = Line { text: text.to_string(), file: file_name.clone(), number: idx + 1 };
+            let Some(directive) = tokenizer::directive(text, &mut in_comment) else {
+                output.push(line);
+                continue;
+            };
+            if skip.contains(&idx) || self.pragma_once_regex.is_match(text) {
+                if self.opts.keep_directives {
+                    output.push(line.removed());
+                }
+                continue;
+            }
+            // Conditionals are left for `evaluate_conditionals` to remove, but nothing in a
+            // dead branch is included or defined
+            let conditional = self.track_conditional(directive, &line, &mut blocks)?;
+            if conditional || !blocks.last().is_none_or(|block| block.live) {
+                output.push(line);
+                continue;
+            }
+            let computed;
+            let captures = match self.include_regex.captures(text) {
+                Some(captures) => captures,
+                None => match self.computed_include_regex.captures(text) {
+                    Some(captures) => {
+                        let tokens = captures.get(1).unwrap().as_str();
+                        let trace = macros::Trace { opts: self.opts, location: line.location() };
+                        let (table, used) = (&self.macros, &mut *self.used);
+                        let (expansions, counter) = (&mut self.stats.macro_expansions, &mut self.counter);
+                        let result = macros::expand_line(tokens, table, &mut false, used, &trace, expansions, counter);
+                        let expanded =
+                            result.map_err(|msg| PreprocessError::MacroError { location: line.location(), msg })?;
+                        computed = format!("#include {}", expanded.trim());
+                        self.include_regex.captures(&computed).ok_or_else(|| PreprocessError::InvalidDirective {
+                            location: line.location(),
+                            msg: format!("'{}' expands to '{}', not \"FILE\" or <FILE>", tokens, expanded.trim()),
+                        })?
+                    }
+                    None => {
+                        self.track_macros(&line);
+                        output.push(line);
+                        continue;
+                    }
+                },
+            };
+
+            let next = captures.get(1).is_some();
+            let quoted = captures.get(2);
+            let header = quoted.or(captures.get(3)).unwrap().as_str();
+            if quoted.is_none() && self.opts.inline_system == InlineSystem::Never {
+                let message = format_args!("{}: keeping '{}', a system header", line.location(), text.trim());
+                log(self.opts, Verbosity::Verbose, message);
+                output.push(line);
+                continue;
+            }
+            // `#include_next` carries on from the include directory after the current file's
+            let local_dir = quoted.is_some().then(|| local_dir(file, self.stack.len() == 1, self.opts));
+            let resolved = match self.stack.last().unwrap().2 {
+                Some(dir) if next => resolve_header(header, None, self.opts, dir + 1),
+                _ if next => resolve_header(header, None, self.opts, 0),
+                _ => resolve_header(header, local_dir, self.opts, 0),
+            };
+            let required = quoted.is_some() || self.opts.inline_system == InlineSystem::Always;
+            let (header_path, dir) = match resolved {
+                Ok(found) => found,
+                Err(searched) if required && !next => {
+                    return Err(PreprocessError::IncludeNotFound {
+                        location: line.location(),
+                        path: header.to_string(),
+                        searched,
+                    });
+                }
+                Err(_) => {
+                    if next {
+                        let message = format_args!(
+                            "{}: warning: '{}' not found in any further include directory",
+                            line.location(),
+                            header
+                        );
+                        log(self.opts, Verbosity::Normal, message);
+                        *self.warnings += 1;
+                    } else {
+                        let message = format_args!("{}: keeping '{}', not found", line.location(), text.trim());
+                        log(self.opts, Verbosity::Verbose, message);
+                    }
+                    output.push(line);
+                    continue;
+                }
+            };
+
+            let canonical = fs::canonicalize(&header_path)?;
+            if let Some(start) = self.stack.iter().position(|(open, ..)| *open == canonical) {
+                let mut chain: Vec<String> = self.stack[start..].iter().map(|(_, name, _)| name.clone()).collect();
+                chain.push(header_path.display().to_string());
+                return Err(PreprocessError::CircularInclude(chain));
+            }
+            if !self.headers.contains(&header_path) {
+                if dir.is_some_and(|dir| dir >= self.opts.include_dirs.len()) {
+                    self.system_headers.push(header_path.clone());
+                }
+                self.headers.push(header_path.clone());
+            }
+            if self.opts.keep_directives {
+                output.push(line.removed());
+            }
+            let location = line.location();
+            if self.visited.contains(&canonical) {
+                let message = format_args!("{}: skipping '{}', already included", location, header_path.display());
+                log(self.opts, Verbosity::Verbose, message);
+                continue;
+            }
+
+            let header_content = read_source(&header_path, &canonical, self.opts)?;
+            let once = header_content.lines().any(|text| self.pragma_once_regex.is_match(text));
+            if once || self.opts.include_once_all {
+                self.visited.insert(canonical.clone());
+            }
+            let mut guard_lines = Vec::new();
+            if let Some((guard, [ifndef, _, endif])) = find_include_guard(&header_content) {
+                if self.defines.contains_key(&guard) {
+                    let message = format_args!(
+                        "{}: skipping '{}', its guard '{}' is already defined",
+                        location,
+                        header_path.display(),
+                        guard
+                    );
+                    log(self.opts, Verbosity::Verbose, message);
+                    continue;
+                }
+                guard_lines.extend([ifndef, endif]);
+            }
+            let message = format_args!("{}: including '{}'", location, header_path.display());
+            log(self.opts, Verbosity::Verbose, message);
+
+            if self.stack.len() > self.opts.max_include_depth {
+                return Err(PreprocessError::IncludeTooDeep {
+                    location: line.location(),
+                    path: header_path.display().to_string(),
+                    depth: self.opts.max_include_depth,
+                });
+            }
+            self.stats.includes += 1;
+            self.transformations.push(Transformation::Inlined { location, path: header_path.clone() });
+            self.stack.push((canonical, header_path.display().to_string(), dir));
+            self.expand(&header_content, &header_path, &guard_lines, output)?;
+            self.stack.pop();
+        }
+
+        Ok(())
+    }
+
+    /// Applies `line` to the macro tables if it is a `#define` or `#undef`.
+    fn track_macros(&mut self, line: &Line) {
+        if let Some(captures) = self.define_regex.captures(&line.text) {
+            let (name, definition) = parse_define(&line.text, &captures, line.location());
+            let value = strip_comments(&line.text[captures.get(1).unwrap().end()..]);
+            self.defines.insert(name.to_string(), value.trim().to_string());
+            self.macros.insert(name.to_string(), definition);
+        } else if let Some(captures) = self.undef_regex.captures(&line.text) {
+            self.defines.remove(&captures[1]);
+            self.macros.remove(&captures[1]);
+        }
+    }
+
+    /// Follows `line` in `blocks`, the conditional blocks open in the file being expanded, if
+    /// it is a conditional directive named `directive`, and returns whether it is one. Each
+    /// branch is found live or dead the same way [`evaluate_conditionals`] will find it, but
+    /// imbalances are left for it to report.
+    fn track_conditional(
+        &mut self,
+        directive: &str,
+        line: &Line,
+        blocks: &mut Vec<Conditional>,
+    ) -> Result<bool, PreprocessError> {
+        if !matches!(directive, "if" | "ifdef" | "ifndef" | "elif" | "else" | "endif") {
+            return Ok(false);
+        }
+        let captures = self.conditional_regex.captures(&line.text);
+        let rest = strip_comments(captures.as_ref().map_or("", |captures| captures.get(1).unwrap().as_str()));
+        let rest = rest.trim();
+        let (opts, main) = (self.opts, self.stack.len() == 1);
+        let has_include = |header: &str, quoted: bool| {
+            let local_dir = quoted.then(|| local_dir(Path::new(&*line.file), main, opts));
+            resolve_header(header, local_dir, opts, 0).is_ok()
+        };
+        let mut condition = |expr: &str| {
+            expr::evaluate(expr, &self.defines, &has_include, self.used)
+                .map(|value| value != 0)
+                .map_err(|msg| PreprocessError::InvalidDirective { location: line.location(), msg })
+        };
+
+        let live = blocks.last().is_none_or(|block| block.live);
+        match (directive, blocks.last_mut()) {
+            ("if" | "ifdef" | "ifndef", _) => {
+                let selected = live
+                    && match directive {
+                        "if" => condition(rest)?,
+                        _ => {
+                            let name = rest.split(|c: char| !c.is_alphanumeric() && c != '_').next().unwrap();
+                            self.defines.contains_key(name) == (directive == "ifdef")
+                        }
+                    };
+                blocks.push(Conditional {
+                    location: line.location(),
+                    parent_live: live,
+                    live: selected,
+                    taken: selected,
+                    in_else: false,
+                });
+            }
+            ("elif", Some(block)) if !block.in_else => {
+                block.live = block.parent_live && !block.taken && condition(rest)?;
+                block.taken |= block.live;
+            }
+            ("else", Some(block)) if !block.in_else => {
+                block.in_else = true;
+                block.live = block.parent_live && !block.taken;
+                block.taken = true;
+            }
+            ("endif", Some(_)) => {
+                blocks.pop();
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+}
+
+/// The directory the quoted includes in `file` are looked up in first: the one it is in, or
+/// [`Options::base_dir`] if `file` is the source being preprocessed (`main`) and it is set.
+fn local_dir<'a>(file: &'a Path, main: bool, opts: &'a Options) -> &'a Path {
+    match &opts.base_dir {
+        Some(dir) if main => dir,
+        _ => file.parent().unwrap_or(Path::new("")),
+    }
+}
+
+/// Finds `header` in `local_dir` (for quoted includes), then in each of the directories
+/// [`search_dirs`] lists in order, starting from the one at index `first_dir`. Returns the
+/// path found along with the index of the include directory it is in, if any. On failure
+/// returns the list of paths that were tried.
+fn resolve_header(
+    header: &str,
+    local_dir: Option<&Path>,
+    opts: &Options,
+    first_dir: usize,
+) -> Result<(PathBuf, Option<usize>), Vec<String>> {
+    let local_dir = local_dir.map(|dir| (None, dir.join(header)));
+    let include_dirs = search_dirs(opts).into_iter().enumerate().skip(first_dir);
+    let candidates: Vec<(Option<usize>, PathBuf)> = local_dir
+        .into_iter()
+        .chain(include_dirs.map(|(idx, dir)| (Some(idx), dir.join(header))))
+        .collect();
+
+    match candidates.iter().find(|(_, path)| path.is_file()) {
+        Some((idx, found)) => Ok((found.clone(), *idx)),
+        None => Err(candidates.iter().map(|(_, path)| path.display().to_string()).collect()),
+    }
+}
+
+/// The include directories, in the order they are searched: [`Options::include_dirs`], then
+/// the system directories, first [`Options::system_include_dirs`] and then those under
+/// [`Options::sysroot`].
+fn search_dirs(opts: &Options) -> Vec<PathBuf> {
+    let sysroot_dirs = ["usr/local/include", "usr/include"];
+    let sysroot_dirs = opts.sysroot.iter().flat_map(|root| sysroot_dirs.map(|dir| root.join(dir)));
+    opts.include_dirs.iter().chain(&opts.system_include_dirs).cloned().chain(sysroot_dirs).collect()
+}
+
+/// Detects a classic include guard wrapping the whole of `code`:
+///
+/// ```c
+/// #ifndef FOO_H
+/// #define FOO_H
+/// ...
+/// #endif
+/// ```
+///
+/// The `#ifndef`/`#define` pair must be the first two non-blank lines and the matching
+/// `#endif` the last one. Returns the guard macro and the 0-based indices of the three guard
+/// lines, or `None` if the code isn't guarded this way.
+fn find_include_guard(code: &str) -> Option<(String, [usize; 3])> {
+    let ifndef_regex = regex(r"^\s*#\s*ifndef\s+(\w+)\s*$");
+    let define_regex = regex(r"^\s*#\s*define\s+(\w+)\s*$");
+    let open_regex = regex(r"^\s*#\s*if(?:n?def)?\b");
+    let endif_regex = regex(r"^\s*#\s*endif\b");
+
+    let lines: Vec<&str> = code.lines().collect();
+    let mut non_blank = lines.iter().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let (ifndef_idx, ifndef_line) = non_blank.next()?;
+    let (define_idx, define_line) = non_blank.next()?;
+    let (endif_idx, _) = non_blank.next_back()?;
+
+    let guard = ifndef_regex.captures(ifndef_line)?.get(1)?.as_str();
+    if define_regex.captures(define_line)?.get(1)?.as_str() != guard {
+        return None;
+    }
+
+    // The final #endif must close the guard's #ifndef rather than some later block.
+    let mut depth = 0;
+    for (idx, line) in lines.iter().enumerate().take(endif_idx + 1).skip(ifndef_idx) {
+        if open_regex.is_match(line) {
+            depth += 1;
+        } else if endif_regex.is_match(line) {
+            depth -= 1;
+            if depth == 0 && idx != endif_idx {
+                return None;
+            }
+        }
+    }
+    if depth != 0 {
+        return None;
+    }
+
+    Some((guard.to_string(), [ifndef_idx, define_idx, endif_idx]))
+}
+
+/// One open conditional block while scanning in [`evaluate_conditionals`].
+struct Conditional {
+    /// Where the opening directive is, for error messages.
+    location: String,
+    /// Whether the code around this block is being kept.
+    parent_live: bool,
+    /// Whether the branch currently being scanned is kept.
+    live: bool,
+    /// Whether some branch of this chain has already been selected.
+    taken: bool,
+    /// Whether the `#else` branch has been reached.
+    in_else: bool,
+}
+
+/// Keeps only the selected branch of each `#if`/`#ifdef`/`#ifndef` ... `#elif`/`#else` ...
+/// `#endif` chain.
+///
+/// `macros` seeds the table of defined macros; every `#define` and `#undef` in kept code
+/// updates it as the scan reaches it, and `#if`/`#elif` expressions are evaluated against
+/// it. Exactly one branch of each chain survives. The conditional directives themselves and
+/// the dead branches are dropped, blocks nest, and any imbalance is an error naming the
+/// offending line. Directives are found as [`tokenizer::directive`] finds them, so none is
+/// ever read from inside a block comment. An `#error` directive in kept code fails
+/// preprocessing with its message, and a `#warning` prints its message to stderr, adds one
+/// to `warnings` and is dropped; both are ignored in dead branches like the rest of the
+/// branch. A `#pragma` matching one of [`Options::strip_pragmas`] is dropped too, as is the
+/// null directive, a `#` alone on its line. With [`Options::keep_directives`], every dropped line is replaced by a comment
+/// instead. Macros tested by a conditional directive that is evaluated are added to `used`.
+/// `source` is the path of the source being preprocessed, whose lines `__has_include`
+/// resolves quoted names for as its `#include`s do. Every `#if`, `#ifdef`, `#ifndef`,
+/// `#elif` and `#else` reached in live code is added to `transformations` with whether its
+/// branch was kept.
+fn evaluate_conditionals(
+    lines: Vec<Line>,
+    mut macros: HashMap<String, String>,
+    source: &Path,
+    opts: &Options,
+    used: &mut HashSet<String>,
+    warnings: &mut usize,
+    transformations: &mut Vec<Transformation>,
+) -> Result<Vec<Line>, PreprocessError> {
+    let directive_regex = regex(r"^\s*#\s*(ifdef|ifndef|if|elif|else|endif|define|undef|error|warning|pragma)\b\s*(.*)");
+    let name_regex = regex(r"^(\w+)(.*)");
+    let mut stack: Vec<Conditional> = Vec::new();
+    let mut output = Vec::new();
+    let mut in_comment = false;
+
+    for line in lines {
+        let live = stack.last().is_none_or(|block| block.live);
+
+        // A line inside a block comment is never a directive, even if it looks like one
+        let name = tokenizer::directive(&line.text, &mut in_comment);
+        let Some(captures) = name.and_then(|_| directive_regex.captures(&line.text)) else {
+            if live && name != Some("") {
+                output.push(line);
+            } else if opts.keep_directives {
+                output.push(line.removed());
+            }
+            continue;
+        };
+        let removed = opts.keep_directives.then(|| line.removed());
+        let kept = output.len();
+        let directive = captures.get(1).unwrap().as_str();
+        // Comments are no part of an operand, be it an expression, a name or a message
+        let rest = strip_comments(captures.get(2).unwrap().as_str());
+        let rest = rest.trim();
+        let macro_name = || -> Result<&str, PreprocessError> {
+            name_regex.captures(rest).map(|c| c.get(1).unwrap().as_str()).ok_or_else(|| {
+                PreprocessError::InvalidDirective {
+                    location: line.location(),
+                    msg: format!("#{} without a macro name", directive),
+                }
+            })
+        };
+        // `__has_include` looks for headers the way an `#include` on this line would
+        let has_include = |header: &str, quoted: bool| {
+            let main = *line.file == *source.display().to_string();
+            let local_dir = quoted.then(|| local_dir(Path::new(&*line.file), main, opts));
+            resolve_header(header, local_dir, opts, 0).is_ok()
+        };
+        let condition = |expr: &str, macros: &HashMap<String, String>, used: &mut HashSet<String>| {
+            expr::evaluate(expr, macros, &has_include, used)
+                .map(|value| value != 0)
+                .map_err(|msg| PreprocessError::InvalidDirective { location: line.location(), msg })
+        };
+        let unbalanced = |msg: String| PreprocessError::UnbalancedConditional { location: line.location(), msg };
+        let resolved = |taken: bool| {
+            let directive = format!("#{} {}", directive, rest).trim_end().to_string();
+            Transformation::Conditional { location: line.location(), directive, taken }
+        };
+
+        match directive {
+            "ifdef" | "ifndef" | "if" => {
+                let selected = live
+                    && match directive {
+                        "ifdef" | "ifndef" => {
+                            let name = macro_name()?;
+                            let defined = macros.contains_key(name);
+                            if defined {
+                                used.insert(name.to_string());
+                            }
+                            defined == (directive == "ifdef")
+                        }
+                        _ => condition(rest, &macros, used)?,
+                    };
+                if live {
+                    transformations.push(resolved(selected));
+                }
+                stack.push(Conditional {
+                    location: line.location(),
+                    parent_live: live,
+                    live: selected,
+                    taken: selected,
+                    in_else: false,
+                });
+            }
+            "elif" => {
+                let block = stack.last_mut().ok_or_else(|| unbalanced("#elif without #if".into()))?;
+                if block.in_else {
+                    return Err(unbalanced("#elif after #else".into()));
+                }
+                block.live = block.parent_live && !block.taken && condition(rest, &macros, used)?;
+                block.taken |= block.live;
+                if block.parent_live {
+                    transformations.push(resolved(block.live));
+                }
+            }
+            "else" => {
+                let block = stack.last_mut().ok_or_else(|| unbalanced("#else without #if".into()))?;
+                if block.in_else {
+                    let msg = format!("duplicate #else for the block opened at {}", block.location);
+                    return Err(unbalanced(msg));
+                }
+                block.in_else = true;
+                block.live = block.parent_live && !block.taken;
+                block.taken = true;
+                if block.parent_live {
+                    transformations.push(resolved(block.live));
+                }
+            }
+            "endif" => {
+                stack.pop().ok_or_else(|| unbalanced("unbalanced #endif".into()))?;
+            }
+            "undef" => {
+                if live {
+                    macros.remove(macro_name()?);
+                    output.push(line);
+                }
+            }
+            "error" => {
+                if live {
+                    let msg = rest.to_string();
+                    return Err(PreprocessError::ErrorDirective { location: line.location(), msg });
+                }
+            }
+            "warning" => {
+                if live {
+                    log(opts, Verbosity::Normal, format_args!("{}: warning: #warning {}", line.location(), rest));
+                    *warnings += 1;
+                }
+            }
+            "pragma" => {
+                let stripped = opts.strip_pragmas.iter().any(|prefix| {
+                    let prefix: Vec<&str> = prefix.split_whitespace().collect();
+                    !prefix.is_empty() && rest.split_whitespace().take(prefix.len()).eq(prefix)
+                });
+                if live && !stripped {
+                    output.push(line);
+                }
+            }
+            _ => {
+                if live {
+                    let captures = name_regex.captures(rest).ok_or_else(|| macro_name().unwrap_err())?;
+                    macros.insert(captures[1].to_string(), captures[2].trim().to_string());
+                    output.push(line);
+                }
+            }
+        }
+        if output.len() == kept {
+            output.extend(removed);
+        }
+    }
+
+    if let Some(block) = stack.last() {
+        return Err(PreprocessError::UnbalancedConditional {
+            location: block.location.clone(),
+            msg: "conditional block is never closed with #endif".into(),
+        });
+    }
+
+    Ok(output)
+}
+
+/// Blanks out comments and the contents of string and character literals in `text` so that
+/// pattern matching doesn't mistake e.g. `printf("int main() {")` or a commented-out brace
+/// for code. Each masked character is replaced by as many spaces as it has bytes and the
+/// quotes are kept, so any span matched in the masked text selects the same code in the
+/// original. `in_comment` says whether a block comment is open at the start of the line and
+/// is updated for the next one.
+fn mask_code(text: &str, in_comment: &mut bool) -> String {
+    let mut masked = String::with_capacity(text.len());
+    let blank = |masked: &mut String, text: &str| masked.extend(std::iter::repeat_n(' ', text.len()));
+
+    for token in tokenizer::tokenize(text, in_comment) {
+        match token.kind {
+            tokenizer::Kind::Comment => blank(&mut masked, token.text),
+            tokenizer::Kind::String | tokenizer::Kind::Char => {
+                // Quotes are ASCII, so a byte offset either side of them is a char boundary
+                let quote = token.text.chars().next().unwrap();
+                let closed = tokenizer::literal_len(token.text, quote).is_some();
+                masked.push(quote);
+                blank(&mut masked, &token.text[1..token.text.len() - closed as usize]);
+                if closed {
+                    masked.push(quote);
+                }
+            }
+            _ => masked.push_str(token.text),
+        }
+    }
+
+    masked
+}
+
+/// What kind of top-level definition an [`Element`] is. Types are emitted first, then global
+/// variables, then functions, which is a valid order as long as no global's initializer
+/// takes the address of a function.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    /// A `struct`, `union` or `enum` definition, or a `typedef`.
+    Type,
+    /// A variable declared outside any function, e.g. `static int counter = 0;`.
+    Global,
+    Function,
+}
+
+/// A top-level definition found by [`find_elements`].
+struct Element {
+    kind: Kind,
+    /// The names other elements refer to it by: a function's name, the variables a global
+    /// declaration declares, or a type's tag, the names a `typedef` declares and an `enum`'s
+    /// enumerators.
+    names: Vec<String>,
+    /// Index of the line the definition starts on, including the comment attached to it.
+    start: usize,
+    /// Index of the line it ends on; for a function, the one closing its body.
+    end: usize,
+    /// A forward declaration that lets uses precede the definition, if it has one.
+    declaration: Option<String>,
+}
+
+/// Finds the top-level definitions in `lines`, which `masked` holds masked with
+/// [`mask_code`]: function definitions, from the signature to the brace closing the body,
+/// `struct`, `union` and `enum` definitions, `typedef`s and global variable declarations.
+/// Each includes the run of comment lines directly above it, such as a doc comment, though not
+/// the comments [`Options::keep_directives`] leaves for removed lines. Returns them in source
+/// order, along with the details of each function.
+///
+/// A function signature may be split over up to [`MAX_SIGNATURE_LINES`] lines, with the
+/// body's opening brace on the last. Prototypes, which end in `;` rather than a body, aren't
+/// definitions and stay where they are, as do forward declarations like `struct node;` and
+/// any other line outside the definitions. A global function pointer, such as
+/// `static void (*handler)(void) = on_event;`, is a global named `handler`.
+/// Defining two functions with the same name prints a warning to stderr and adds one to
+/// `warnings`; uses of the name then refer to the first. Signatures are matched with
+/// [`Options::function_pattern`] if it is set, and otherwise with [`FUNCTION_PATTERN`].
+fn find_elements(
+    lines: &[Line],
+    masked: &[String],
+    opts: &Options,
+    warnings: &mut usize,
+) -> (Vec<Element>, Vec<Function>) {
+    let mut elements = Vec::new();
+    let mut functions: Vec<Function> = Vec::new();
+
+    // Detect definitions and find where each body ends by counting braces. A function's name
+    // follows any number of specifiers/qualifiers and `*`s, e.g. `static const char *name(`.
+    let function_regex = opts.function_pattern.clone().unwrap_or_else(|| regex(FUNCTION_PATTERN));
+    let type_regex = regex(r"^\s*(typedef\s+)?(struct|union|enum)\b\s*(\w+)?\s*\{");
+    let typedef_regex = regex(r"^\s*typedef\b[^{]*;");
+    let fn_pointer_regex = regex(r"\(\s*\*\s*(\w+)\s*\)");
+    let global_regex = regex(r"^\s*((?:[A-Za-z_]\w*[\s*]+)+)[A-Za-z_]\w*\s*(?:\[[^\]]*\]\s*)*[=,;]");
+    let fn_pointer_global_regex = regex(r"^\s*((?:[A-Za-z_]\w*[\s*]+)+)\(\s*\*\s*(\w+)\s*\)\s*\([^;]*\)\s*[=;]");
+    let forward_regex = regex(r"^\s*(struct|union|enum)\s+\w+\s*;");
+    let ident_regex = regex(r"[A-Za-z_]\w*");
+    let mut idx = 0;
+    while idx < masked.len() {
+        let text = &masked[idx];
+        // Blank and comment-only lines never start a definition, though a comment can join one
+        if text.trim().is_empty() {
+            idx += 1;
+            continue;
+        }
+        // The lines a function signature starting here would span, up to the opening brace
+        let signature_end = (idx..masked.len().min(idx + MAX_SIGNATURE_LINES))
+            .take_while(|&end| end == idx || !masked[end].trim_start().starts_with('#'))
+            .find(|&end| masked[end].contains(['{', ';']));
+        let signature = signature_end.map(|end| masked[idx..=end].join(" "));
+
+        let mut element = if let Some(captures) = type_regex.captures(text) {
+            let end = body_end(masked, idx);
+            let keyword = &captures[2];
+            let mut names: Vec<String> = captures.get(3).into_iter().map(|tag| tag.as_str().to_string()).collect();
+            let declaration = match captures.get(3) {
+                Some(tag) if keyword != "enum" => Some(format!("{} {};", keyword, tag.as_str())),
+                _ => None,
+            };
+            // A body never closed runs to the end of its last line
+            let close = masked[end].rfind('}').unwrap_or(masked[end].len());
+            if keyword == "enum" {
+                // Each enumerator is the first identifier of a comma-separated item in the body
+                let open = text.find('{').unwrap();
+                let body = if end == idx {
+                    text[open + 1..close].to_string()
+                } else {
+                    let mut body = text[open + 1..].to_string();
+                    masked[idx + 1..end].iter().for_each(|line| body.push_str(line));
+                    body + &masked[end][..close]
+                };
+                let enumerators = body.split(',').filter_map(|item| ident_regex.find(item));
+                names.extend(enumerators.map(|m| m.as_str().to_string()));
+            }
+            if captures.get(1).is_some() {
+                let declarators = &masked[end][close..];
+                names.extend(ident_regex.find_iter(declarators).map(|m| m.as_str().to_string()));
+            }
+            // Declarators after the body, like `pts` in `struct P {...} pts[] = {...};`, and
+            // their initializers are part of the definition too
+            Element { kind: Kind::Type, names, start: idx, end: declaration_end(masked, idx), declaration }
+        } else if typedef_regex.is_match(text) {
+            // The declared name is the one in `(*name)` for a function pointer, and otherwise
+            // the last identifier before the `;`
+            let declarator = &text[..text.rfind(';').unwrap()];
+            let name = match fn_pointer_regex.captures(declarator) {
+                Some(captures) => captures[1].to_string(),
+                None => ident_regex.find_iter(declarator).last().map_or_else(String::new, |m| m.as_str().to_string()),
+            };
+            Element { kind: Kind::Type, names: vec![name], start: idx, end: idx, declaration: None }
+        } else if let Some(captures) = signature.as_deref().and_then(|text| function_regex.captures(text)).filter(
+            |captures| (1..=3).all(|group| captures.get(group).is_some()) && captures[0].ends_with('{'),
+        ) {
+            // Rule out statements that look like signatures, such as `else if (x) {`
+            let specifiers = captures[1].split(|c: char| c.is_whitespace() || c == '*');
+            if is_statement_keyword(&captures[2]) || specifiers.clone().any(is_statement_keyword) {
+                idx += 1;
+                continue;
+            }
+            // The original lines joined the same way, so that offsets into the masked
+            // signature apply
+            let original = lines[idx..=signature_end.unwrap()].iter().map(|line| line.text.as_str());
+            let original = original.collect::<Vec<_>>().join(" ");
+            let prototype = original[..captures.get(0).unwrap().end() - 1].split_whitespace();
+            let declaration = Some(format!("{};", prototype.collect::<Vec<_>>().join(" ")));
+            let names = vec![captures[2].to_string()];
+            let params = split_params(&original[captures.get(3).unwrap().range()]);
+            let return_type = captures[1].split_whitespace().collect::<Vec<_>>().join(" ");
+            let (file, line) = (lines[idx].file.to_string(), lines[idx].number);
+            if let Some(first) = functions.iter().find(|function| function.name == names[0]) {
+                log(opts, Verbosity::Normal, format_args!(
+                    "{}: warning: function '{}' is already defined at {}:{}",
+                    lines[idx].location(),
+                    names[0],
+                    first.file,
+                    first.line
+                ));
+                *warnings += 1;
+            }
+            functions.push(Function { name: names[0].clone(), return_type, params, file, line });
+            // Brace counting starts on the line of the body's `{`, which the signature ends with
+            let end = body_end(masked, signature_end.unwrap());
+            Element { kind: Kind::Function, names, start: idx, end, declaration }
+        } else if let Some(captures) = global_regex
+            .captures(text)
+            .or_else(|| fn_pointer_global_regex.captures(text))
+            .filter(|_| !forward_regex.is_match(text))
+        {
+            if captures[1].split(|c: char| c.is_whitespace() || c == '*').any(is_statement_keyword) {
+                idx += 1;
+                continue;
+            }
+            // An initializer may span several lines, with braces nested to any depth
+            let end = declaration_end(masked, idx);
+            let names = match captures.get(2) {
+                Some(name) => vec![name.as_str().to_string()],
+                None => declarator_names(text, &ident_regex),
+            };
+            Element { kind: Kind::Global, names, start: idx, end, declaration: None }
+        } else {
+            idx += 1;
+            continue;
+        };
+
+        // A comment directly above the definition, with no blank line between, belongs to it,
+        // unless it only stands in for a line preprocessing removed there
+        let earliest = elements.last().map_or(0, |previous: &Element| previous.end + 1);
+        while element.start > earliest
+            && masked[element.start - 1].trim().is_empty()
+            && !lines[element.start - 1].text.trim().is_empty()
+            && !lines[element.start - 1].is_removed()
+        {
+            element.start -= 1;
+        }
+
+        idx = element.end + 1;
+        elements.push(element);
+    }
+
+    (elements, functions)
+}
+
+/// How many lines a function signature may span, including the one with the opening brace.
+const MAX_SIGNATURE_LINES: usize = 8;
+
+/// Splits the text between a function's parentheses into its parameter declarations.
+fn split_params(list: &str) -> Vec<String> {
+    let mut params = Vec::new();
+    let mut depth = 0;
+    let mut param = String::new();
+    for c in list.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                params.push(param.trim().to_string());
+                param.clear();
+                continue;
+            }
+            _ => {}
+        }
+        param.push(c);
+    }
+    params.push(param.trim().to_string());
+    // `()` and `(void)` declare no parameters
+    if params.len() == 1 && (params[0].is_empty() || params[0] == "void") {
+        params.clear();
+    }
+    params
+}
+
+/// Builds the graph of dependencies between the code `elements` found by [`find_elements`],
+/// with a node for each element, at the same index and labelled with its names.
+///
+/// A function has an edge labelled `calls` to every other defined function calling it, so
+/// that callees are ordered before their callers. Types have an edge labelled `uses` to every
+/// element that names them, and globals to every element that refers to the variable. Only
+/// the names of the elements themselves count, so keywords such as the `if` in `if (x)` and
+/// macros such as the `SIZE` in `case SIZE:` or `SIZE(x)` never add an edge.
+fn dependency_graph(masked: &[String], elements: &[Element]) -> DiGraph<String, &'static str> {
+    let mut graph = DiGraph::new();
+    let mut node_map = HashMap::new();
+    for element in elements {
+        let label = if element.names.is_empty() { "(anonymous)".into() } else { element.names.join(", ") };
+        let node = graph.add_node(label);
+        for name in &element.names {
+            node_map.entry(name.clone()).or_insert(node);
+        }
+    }
+
+    // Add an edge from each called function, named type or referenced global to the element
+    // using it
+    let use_regex = regex(r"\b([A-Za-z_]\w*)\b(\s*\()?");
+    for (user_idx, element) in elements.iter().enumerate() {
+        let user = NodeIndex::new(user_idx);
+        let mut used = HashSet::new();
+        for text in &masked[element.start..=element.end] {
+            for captures in use_regex.captures_iter(text) {
+                let Some(&target) = node_map.get(&captures[1]) else {
+                    continue;
+                };
+                let edge = match elements[target.index()].kind {
+                    Kind::Function if captures.get(2).is_some() => "calls",
+                    Kind::Function => continue,
+                    _ => "uses",
+                };
+                if target != user && used.insert(target) {
+                    graph.add_edge(target, user, edge);
+                }
+            }
+        }
+    }
+
+    graph
+}
+
+/// Reorders the code `elements` found by [`find_elements`] so that each follows everything it
+/// depends on in `graph`, as built by [`dependency_graph`].
+///
+/// Elements are emitted whole, types first and functions last, and each that ends up in the
+/// place of a different one is preceded by a `// Function start:`, `// Type start:` or
+/// `// Global start:` marker, so that a file already in order comes out unchanged. Elements
+/// that don't depend on each other keep their source order, so the output is the same on
+/// every run. The reordered elements fill the places of the original ones in turn, and
+/// everything outside them, like directives, comments and blank lines, stays where it was.
+/// Mutually recursive functions can't all precede each other, so a prototype is emitted
+/// ahead of the functions for one member of each cycle, and likewise a `struct` declaration
+/// for types that refer to each other.
+///
+/// Directives that reach the output, like `#pragma pack(1)` or a kept `#include <...>`, are
+/// anchored: elements are only reordered among those between the same two of them, so each
+/// directive keeps its place relative to the code around it. A definition used above such a
+/// directive but defined below it therefore stays below it.
+///
+/// Each function that ends up in a different position among the functions is added to
+/// `transformations`.
+fn reorder_elements(
+    lines: &[Line],
+    elements: &[Element],
+    mut graph: DiGraph<String, &'static str>,
+    opts: &Options,
+    transformations: &mut Vec<Transformation>,
+) -> Result<Vec<Line>, PreprocessError> {
+    // Number each run of elements between anchored directives, and drop the edges that would
+    // move an element out of its run. Macros are expanded by now, so every directive left is
+    // one that is output
+    let mut in_comment = false;
+    let directives: Vec<_> = lines.iter().map(|line| tokenizer::directive(&line.text, &mut in_comment)).collect();
+    let mut segments = Vec::with_capacity(elements.len());
+    let mut segment = 0;
+    let mut next_line = 0;
+    for element in elements {
+        let anchors = directives[next_line..element.start].iter().flatten();
+        segment += anchors.count();
+        segments.push(segment);
+        next_line = element.end + 1;
+    }
+    graph.retain_edges(|graph, edge| {
+        let (from, to) = graph.edge_endpoints(edge).unwrap();
+        segments[from.index()] == segments[to.index()]
+    });
+
+    // A cycle (mutual recursion) is broken by forward-declaring one of its elements, after
+    // which nothing needs to follow that element's definition
+    let mut declared = Vec::new();
+    while let Err(cycle) = toposort(&graph, None) {
+        let node = cycle.node_id();
+        let location = lines[elements[node.index()].start].location();
+        let message =
+            format_args!("{}: declaring '{}' ahead of its definition to break a cycle", location, graph[node]);
+        log(opts, Verbosity::Trace, message);
+        declared.push(node);
+        graph.retain_edges(|graph, edge| graph.edge_endpoints(edge).unwrap().0 != node);
+    }
+    declared.sort();
+    let sorted_nodes = stable_toposort(&graph);
+    let mut placed = Vec::with_capacity(elements.len());
+    let mut order = Vec::with_capacity(elements.len());
+
+    // Each kind's forward declarations go just ahead of its first definition in the run,
+    // after everything the declarations themselves may name
+    let kinds = [Kind::Type, Kind::Global, Kind::Function];
+    for (segment, kind) in (0..=segment).flat_map(|segment| kinds.map(|kind| (segment, kind))) {
+        let in_run = |node: &&NodeIndex| segments[node.index()] == segment && elements[node.index()].kind == kind;
+        let mut chunk = Vec::new();
+        for node in declared.iter().filter(in_run) {
+            let element = &elements[node.index()];
+            if let Some(declaration) = &element.declaration {
+                chunk.push(Line { text: declaration.clone(), ..lines[element.start].clone() });
+            }
+        }
+        for node in sorted_nodes.iter().filter(in_run) {
+            let element = &elements[node.index()];
+            let line = &lines[element.start];
+            let marker = match element.kind {
+                Kind::Type => "Type",
+                Kind::Global => "Global",
+                Kind::Function => "Function",
+            };
+            let name = element.names.first().map_or("(anonymous)", String::as_str);
+            if opts.verbosity >= Verbosity::Trace {
+                let mut after: Vec<_> = graph
+                    .neighbors_directed(*node, Direction::Incoming)
+                    .map(|dep| format!("'{}'", graph[dep]))
+                    .collect();
+                after.reverse();
+                let after = if after.is_empty() { "nothing".into() } else { after.join(", ") };
+                let message = format_args!("{}: placing '{}' after {}", line.location(), graph[*node], after);
+                log(opts, Verbosity::Trace, message);
+            }
+            // The elements are placed in turn, so an element stays put if it is placed at its own index
+            if node.index() != placed.len() {
+                chunk.push(Line { text: format!("// {} start: {}", marker, name), ..line.clone() });
+            }
+            chunk.extend_from_slice(&lines[element.start..=element.end]);
+            placed.push(std::mem::take(&mut chunk));
+            order.push(node.index());
+        }
+    }
+
+    // Each function placed differently among the functions than it was defined is reported
+    let is_function = |idx: &usize| elements[*idx].kind == Kind::Function;
+    let placed_functions: Vec<usize> = order.iter().copied().filter(is_function).collect();
+    for (from, idx) in (0..elements.len()).filter(is_function).enumerate() {
+        let to = placed_functions.iter().position(|&placed| placed == idx).unwrap();
+        if to != from {
+            let name = elements[idx].names[0].clone();
+            transformations.push(Transformation::Reordered { name, from: from + 1, to: to + 1 });
+        }
+    }
+
+    // The elements, which are in source order, give up their places to the reordered ones in
+    // turn, and the code between them stays where it is
+    let mut reordered_lines = Vec::with_capacity(lines.len() + 2 * elements.len());
+    let mut next_line = 0;
+    for (element, chunk) in elements.iter().zip(placed) {
+        reordered_lines.extend_from_slice(&lines[next_line..element.start]);
+        reordered_lines.extend(chunk);
+        next_line = element.end + 1;
+    }
+    reordered_lines.extend_from_slice(&lines[next_line..]);
+
+    Ok(reordered_lines)
+}
+
+/// Sorts the nodes of the acyclic `graph` so that each follows all of its predecessors,
+/// keeping nodes in index order wherever the edges allow, so that the same graph always
+/// sorts the same way and unrelated elements keep their source order.
+fn stable_toposort<N, E>(graph: &DiGraph<N, E>) -> Vec<NodeIndex> {
+    let mut pending: Vec<usize> =
+        graph.node_indices().map(|node| graph.neighbors_directed(node, Direction::Incoming).count()).collect();
+    let mut ready: BTreeSet<NodeIndex> = graph.node_indices().filter(|node| pending[node.index()] == 0).collect();
+    let mut sorted = Vec::with_capacity(graph.node_count());
+    while let Some(node) = ready.pop_first() {
+        sorted.push(node);
+        for next in graph.neighbors(node) {
+            pending[next.index()] -= 1;
+            if pending[next.index()] == 0 {
+                ready.insert(next);
+            }
+        }
+    }
+    sorted
+}
+
+/// The names declared by the global variable declaration starting on `text`, e.g. `a` and `b`
+/// for `static int *a = &x, b[4];`: the last identifier of each top-level comma-separated
+/// declarator, ignoring array sizes and initializers.
+fn declarator_names(text: &str, ident_regex: &Regex) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut depth = 0;
+    let mut declarator = String::new();
+    let mut in_initializer = false;
+    for c in text.chars() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            '=' if depth == 0 => in_initializer = true,
+            ',' | ';' if depth == 0 => {
+                names.extend(ident_regex.find_iter(&declarator).last().map(|m| m.as_str().to_string()));
+                declarator.clear();
+                in_initializer = false;
+                if c == ';' {
+                    break;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if depth == 0 && !in_initializer && !matches!(c, ')' | ']') {
+            declarator.push(c);
+        }
+    }
+    if !declarator.is_empty() {
+        names.extend(ident_regex.find_iter(&declarator).last().map(|m| m.as_str().to_string()));
+    }
+    names
+}
+
+/// The Rust item standing in for the macro `name`: an `i64` constant for an integer literal,
+/// a `&str` constant for a string literal, and otherwise a comment giving the definition,
+/// which has no Rust type.
+fn rust_const(name: &str, definition: &Macro) -> String {
+    let body = definition.body.trim();
+    if definition.params.is_none() {
+        if let Some(value) = c_integer(body) {
+            return format!("const {}: i64 = {};", name, value);
+        }
+        if let Some(text) = c_string(body) {
+            return format!("const {}: &str = {:?};", name, text);
+        }
+    }
+    format!("// {} has no Rust type: {}", name, definition.definition())
+}
+
+/// Reads `text` as a C integer literal, optionally negated, in decimal, octal or hex and
+/// with any `u` and `l` suffixes, and returns it as a Rust `i64` literal, or `None` if it
+/// isn't one or doesn't fit.
+fn c_integer(text: &str) -> Option<String> {
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(rest) => ("-", rest.trim_start()),
+        None => ("", text),
+    };
+    let digits = digits.trim_end_matches(['u', 'U', 'l', 'L']);
+    let (radix, digits) = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => (16, hex),
+        None if digits.len() > 1 && digits.starts_with('0') => (8, &digits[1..]),
+        None => (10, digits),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let value = i64::from_str_radix(digits, radix).ok()?;
+    Some(if radix == 16 { format!("{}{:#X}", sign, value) } else { format!("{}{}", sign, value) })
+}
+
+/// Decodes `text` if it is a single C string literal, or returns `None` if it isn't one or
+/// has an escape that stands for a byte rather than a character.
+fn c_string(text: &str) -> Option<String> {
+    let inner = text.strip_prefix('"')?.strip_suffix('"')?;
+    let mut decoded = String::with_capacity(inner.len());
+    let mut chars = inner.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return None,
+            '\\' => {
+                let code = expr::escape_value(&mut chars)?;
+                decoded.push(char::from_u32(code).filter(char::is_ascii)?);
+            }
+            c => decoded.push(c),
+        }
+    }
+    Some(decoded)
+}
+
+/// Prints `message` to stderr if `opts` asks for messages at `level`, as every diagnostic is
+/// printed, the command line's own included. Errors are at [`Verbosity::Quiet`], so they
+/// always show.
+pub fn log(opts: &Options, level: Verbosity, message: fmt::Arguments) {
+    if opts.verbosity >= level {
+        eprintln!("{}", message);
+    }
+}
+
+/// Compiles one of the built-in patterns.
+fn regex(pattern: &str) -> Regex {
+    Regex::new(pattern).expect("built-in pattern is valid")
+}
+
+/// Whether `word` is a C keyword that starts or continues a statement, and so can't be part
+/// of a function signature.
+fn is_statement_keyword(word: &str) -> bool {
+    matches!(
+        word,
+        "if" | "else" | "while" | "for" | "do" | "switch" | "case" | "return" | "goto" | "sizeof" | "typedef"
+    )
+}
+
+/// Returns the index of the line whose `}` closes the brace block opened on line `start` of
+/// `lines`, which must already be masked with [`mask_code`] so that braces in comments and
+/// literals don't count. Returns the last line if the block is never closed.
+fn body_end(lines: &[String], start: usize) -> usize {
+    let mut depth = 0;
+    for (idx, line) in lines.iter().enumerate().skip(start) {
+        for c in line.chars() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return idx;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    lines.len().saturating_sub(1)
+}
+
+/// Returns the index of the line whose `;` ends the declaration starting on line `start` of
+/// `lines`: the first `;` outside any braces, so that a body or a brace-enclosed initializer
+/// like `{1, {2}}` is part of it, however far it spans. `lines` must already be masked with
+/// [`mask_code`]. Returns the last line if the declaration never ends.
+fn declaration_end(lines: &[String], start: usize) -> usize {
+    let mut depth = 0;
+    for (idx, line) in lines.iter().enumerate().skip(start) {
+        for c in line.chars() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                ';' if depth <= 0 => return idx,
+                _ => {}
+            }
+        }
+    }
+    lines.len().saturating_sub(1)
+}
+
+/// Handles macros by expanding uses of both object-like and function-like macros in the
+/// live lines. This runs in source order, ahead of [`reorder_elements`], so that a definition
+/// later moved above a `#define` it uses still has the macro expanded. A `#define` is applied
+/// from that line on and then dropped like other directives, or converted into a
+/// Rust-compatible construct for [`Target::Rust`]. `#undef` ends a macro's expansion from
+/// that line on; the line itself has already been applied by [`evaluate_conditionals`] and
+/// is dropped. Dropped lines are commented out instead with [`Options::keep_directives`].
+///
+/// The table starts out with the predefined macros: `__FILE__` and `__LINE__` expand to the
+/// file and line each line was originally read from, `__DATE__` and `__TIME__` to when
+/// preprocessing started, and `__COUNTER__` to `counter`, which is one more at each use,
+/// unless [`Options::no_predefined`] leaves them out. The command-line macros in
+/// [`Options::macros`] are then applied to it without producing any output.
+///
+/// Redefining a macro differently from its current definition prints a warning to stderr
+/// naming both definitions and adds one to `warnings`. The names of the macros expanded are
+/// added to `used`, and the expansions are counted in `stats`. `counter` starts at the value
+/// [`inline_includes`] left it at, so `__COUNTER__` never repeats a value within a run.
+/// A line ending inside the arguments of a function-like macro is joined with the lines after
+/// it until they are closed, and comes out as one line with the origin of the first; the
+/// arguments reaching a directive or the end of the file unclosed is an error. Returns the
+/// lines with their macros expanded, keeping each line's origin, and the macro table as it
+/// stands at the end.
+fn handle_macros(
+    lines: &[Line],
+    opts: &Options,
+    used: &mut HashSet<String>,
+    warnings: &mut usize,
+    stats: &mut Stats,
+    counter: &mut usize,
+) -> Result<(Vec<Line>, HashMap<String, Macro>), PreprocessError> {
+    let macro_regex = regex(DEFINE_PATTERN);
+    let undef_regex = regex(r"^\s*#\s*undef\s+(\w+)");
+    let mut macros = initial_macros(opts);
+    let mut in_comment = false;
+    let mut masking_comment = false;
+    let mut output = Vec::with_capacity(lines.len());
+    // The lines so far, joined, of a macro call whose arguments go on past its first line
+    let mut pending: Option<Line> = None;
+
+    for line in lines {
+        // Match against the masked line, then read the captured text back out of the original
+        let masked = mask_code(&line.text, &mut masking_comment);
+        let joined;
+        let line = match pending.take() {
+            Some(call) if tokenizer::directive(&masked, &mut false).is_none() => {
+                joined = Line { text: format!("{} {}", call.text, line.text), ..call };
+                &joined
+            }
+            // The directive ends the call's arguments before they are closed, which is an error
+            Some(call) => {
+                expand_code_line(&call, &mut macros, opts, &mut in_comment, used, stats, counter)?;
+                line
+            }
+            None => line,
+        };
+        let line_text = line.text.as_str();
+        let undef = undef_regex.captures(&masked);
+        let define = macro_regex.captures(&masked);
+        if let Some(captures) = &undef {
+            let name = &line_text[captures.get(1).unwrap().range()];
+            log(opts, Verbosity::Verbose, format_args!("{}: undefining '{}'", line.location(), name));
+            macros.remove(name);
+        } else if let Some(captures) = &define {
+            let (macro_name, definition) = parse_define(line_text, captures, line.location());
+            if let Some(old) = macros.get(macro_name).filter(|old| !old.same_definition(&definition)) {
+                log(opts, Verbosity::Normal, format_args!(
+                    "{}: warning: '{}' redefined as '{}', was '{}' at {}",
+                    definition.location,
+                    macro_name,
+                    definition.definition(),
+                    old.definition(),
+                    old.location
+                ));
+                *warnings += 1;
+            }
+            let message =
+                format_args!("{}: defining '{}' as '{}'", line.location(), macro_name, definition.definition());
+            log(opts, Verbosity::Verbose, message);
+            macros.insert(macro_name.to_string(), definition);
+        }
+        // Only Rust output has something to show for a `#define`
+        let dropped = undef.is_some() || (define.is_some() && opts.target == Target::C);
+        if dropped && !opts.keep_directives {
+            continue;
+        }
+        let text = if dropped {
+            line.removed().text
+        } else if let Some(captures) = &define {
+            // Convert macros into Rust constants or cfg attributes
+            let macro_name = &line_text[captures.get(1).unwrap().range()];
+            let definition = &macros[macro_name];
+            if definition.params.is_none() && definition.body.is_empty() {
+                format!("#[cfg({})]", macro_name)
+            } else {
+                rust_const(macro_name, &macros[macro_name])
+            }
+        } else if tokenizer::directive(&masked, &mut false).is_some() {
+            line_text.to_string()
+        } else if macros::ends_in_call(line_text, &macros, in_comment) {
+            pending = Some(line.clone());
+            continue;
+        } else {
+            expand_code_line(line, &mut macros, opts, &mut in_comment, used, stats, counter)?
+        };
+        output.push(Line { text, ..line.clone() });
+    }
+    // The end of the file ends the call's arguments before they are closed, which is an error
+    if let Some(call) = pending {
+        expand_code_line(&call, &mut macros, opts, &mut in_comment, used, stats, counter)?;
+    }
+
+    Ok((output, macros))
+}
+
+/// Expands the macros in `line`, which isn't a directive, for [`handle_macros`], with
+/// `__FILE__` and `__LINE__` giving where it was read from.
+fn expand_code_line(
+    line: &Line,
+    macros: &mut HashMap<String, Macro>,
+    opts: &Options,
+    in_comment: &mut bool,
+    used: &mut HashSet<String>,
+    stats: &mut Stats,
+    counter: &mut usize,
+) -> Result<String, PreprocessError> {
+    if let Some(file) = macros.get_mut("__FILE__") {
+        file.body = macros::string_literal(&line.file);
+    }
+    if let Some(number) = macros.get_mut("__LINE__") {
+        number.body = line.number.to_string();
+    }
+    let location = if opts.trace_macros.is_empty() { String::new() } else { line.location() };
+    let trace = macros::Trace { opts, location };
+    let expansions = &mut stats.macro_expansions;
+    macros::expand_line(&line.text, macros, in_comment, used, &trace, expansions, counter)
+        .map_err(|msg| PreprocessError::MacroError { location: line.location(), msg })
+}
+
+/// Writes `lines` to `out`, each ended with `newline`, except for a blank line following
+/// another when [`Options::blank_lines`] collapses them.
+///
+/// With [`Options::line_markers`], a `#line` directive precedes every line that doesn't come
+/// from the line after the one before it. A line borrowing the origin of the line after it,
+/// like the comment [`reorder_elements`] puts before each element it moves, gets no marker
+/// of its own.
+fn write_lines(lines: &[Line], opts: &Options, out: &mut dyn Write, newline: &str) -> Result<(), PreprocessError> {
+    // Where the next output line is known to come from, as far as a compiler reading it knows
+    let mut next_origin: Option<(&str, usize)> = None;
+    // Whether the last line written was blank, for collapsing runs of blank lines
+    let mut blank_before = false;
+
+    for (idx, line) in lines.iter().enumerate() {
+        let text = &line.text;
+        let blank = text.trim().is_empty();
+        if blank && blank_before && opts.blank_lines == BlankLines::Collapse {
+            continue;
+        }
+        blank_before = blank;
+        if opts.line_markers {
+            let origin = (&*line.file, line.number);
+            let borrowed = lines.get(idx + 1).is_some_and(|next| (&*next.file, next.number) == origin);
+            if !borrowed {
+                if next_origin != Some(origin) {
+                    let file = macros::string_literal(&line.file);
+                    write!(out, "#line {} {}{}", line.number, file, newline)?;
+                }
+                next_origin = Some((&*line.file, line.number + 1));
+            }
+        }
+        write!(out, "{}{}", text, newline)?;
+    }
+
+    Ok(())
+}
+
+/// The name and location of each macro `#define`d in the `lines` read from `file` itself,
+/// in order of first definition, given the lines' `masked` text.
+fn own_macros(lines: &[Line], masked: &[String], file: &Path) -> Vec<(String, String)> {
+    let define_regex = regex(DEFINE_PATTERN);
+    let file = file.display().to_string();
+    let mut own: Vec<(String, String)> = Vec::new();
+    for (line, masked) in lines.iter().zip(masked).filter(|(line, _)| *line.file == *file) {
+        if let Some(captures) = define_regex.captures(masked) {
+            let name = &line.text[captures.get(1).unwrap().range()];
+            if !own.iter().any(|(known, _)| known == name) {
+                own.push((name.to_string(), line.location()));
+            }
+        }
+    }
+    own
+}
+
+/// Matches the signature of a function definition, through the `{` opening its body, as
+/// joined onto one line: any specifiers, qualifiers or `*`s making up the return type, the
+/// name and the parameter list are captured in that order.
+pub const FUNCTION_PATTERN: &str = r"^\s*((?:[A-Za-z_]\w*[\s*]+)+)([A-Za-z_]\w*)\s*\(([^;]*)\)\s*\{";
+
+/// Matches a `#define` line, capturing the name, the parameter list of a function-like macro
+/// and the body. Like every directive pattern, it is anchored to the start of the line, as a
+/// `#` anywhere else never starts a directive.
+const DEFINE_PATTERN: &str = r"^\s*#\s*define\s+(\w+)(\([^)]*\))?\s*(.*)";
+
+/// The macro table before any source is read: the predefined macros, plus those of
+/// [`Options::standard`], with the command-line [`Options::macros`] applied. With
+/// [`Options::no_predefined`], only the command-line macros.
+fn initial_macros(opts: &Options) -> HashMap<String, Macro> {
+    let macro_regex = regex(DEFINE_PATTERN);
+    let mut macros = if opts.no_predefined { HashMap::new() } else { macros::predefined() };
+    if let Some(standard) = opts.standard.filter(|_| !opts.no_predefined) {
+        for (name, body) in [("__STDC__", "1"), ("__STDC_VERSION__", standard.version())] {
+            let definition = Macro { params: None, body: body.into(), location: "<built-in>".into() };
+            macros.insert(name.into(), definition);
+        }
+    }
+    for (name, value) in &opts.macros {
+        let Some(value) = value else {
+            macros.remove(name);
+            continue;
+        };
+        let definition = format!("#define {} {}", name, value);
+        let captures = macro_regex.captures(&definition).unwrap();
+        let (name, definition) = parse_define(&definition, &captures, "<command line>".into());
+        macros.insert(name.to_string(), definition);
+    }
+    macros
+}
+
+/// Reads the name and definition of a macro out of `text`, a `#define` line found at
+/// `location` that [`DEFINE_PATTERN`] matched with `captures`. Comments are no part of the
+/// body, so one trailing the line doesn't swallow the rest of each line it expands into.
+fn parse_define<'a>(text: &'a str, captures: &regex::Captures, location: String) -> (&'a str, Macro) {
+    let params = captures.get(2).map(|list| {
+        let list = text[list.range()].trim_matches(|c| c == '(' || c == ')');
+        list.split(',').map(str::trim).filter(|p| !p.is_empty()).map(String::from).collect()
+    });
+    let body = strip_comments(&text[captures.get(3).unwrap().start()..]).trim().to_string();
+    (&text[captures.get(1).unwrap().range()], Macro { params, body, location })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Preprocesses `source` with the default options.
+    fn run(source: &str) -> String {
+        preprocess(source, &Options::default()).unwrap()
+    }
+
+    /// A fresh, empty directory for the test `name` under the system's temporary directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("c_preprocessor-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Preprocesses `source` as the file `main.c` in `dir` with `opts`.
+    fn run_in(dir: &Path, source: &str, opts: &Options) -> Result<Output, PreprocessError> {
+        preprocess_named(source, &dir.join("main.c"), opts)
+    }
+
+    /// Where `needle` first appears in `code`, which it must.
+    fn position(code: &str, needle: &str) -> usize {
+        code.find(needle).unwrap_or_else(|| panic!("{:?} not in:\n{}", needle, code))
+    }
+
+    #[test]
+    fn types_precede_the_functions_using_them() {
+        let code = run("double norm(struct Point *p) { return p->x; }\nstruct Point { double x, y; };\n");
+        assert!(position(&code, "struct Point {") < position(&code, "double norm("));
+    }
+
+    #[test]
+    fn pragmas_stay_between_the_definitions_around_them() {
+        let code = run("struct A { int a; };\n#pragma pack(1)\nstruct B { struct A a; char c; };\n");
+        assert!(position(&code, "struct A {") < position(&code, "#pragma pack(1)"));
+        assert!(position(&code, "#pragma pack(1)") < position(&code, "struct B {"));
+    }
+
+    #[test]
+    fn functions_moved_above_a_define_have_it_expanded() {
+        let code = run("void a(void){b();}\n#define N 3\nint b(void){return N;}\n");
+        assert!(code.contains("int b(void){return 3;}"));
+        assert!(position(&code, "int b(void)") < position(&code, "void a(void)"));
+    }
+
+    #[test]
+    fn types_moved_above_a_define_have_it_expanded() {
+        let code = run("int g;\n#define SIZE 10\nstruct S { char b[SIZE]; };\n");
+        assert!(code.contains("struct S { char b[10]; };"));
+    }
+
+    #[test]
+    fn defines_in_live_branches_feed_later_conditionals() {
+        let source = "#ifdef A\n#define B\n#endif\n#ifdef B\nint b;\n#endif\n";
+        let with_a = Options { macros: vec![("A".into(), Some(String::new()))], ..Options::default() };
+        assert!(preprocess(source, &with_a).unwrap().contains("int b;"));
+        assert!(!run(source).contains("int b;"));
+    }
+
+    #[test]
+    fn removed_directives_stay_where_they_were() {
+        let opts = Options { keep_directives: true, ..Options::default() };
+        let code = preprocess("void a(void){b();}\n#ifdef X\n#endif\nint b(void){return 1;}\n", &opts).unwrap();
+        assert!(position(&code, "int b(void)") < position(&code, "// [pp] removed: #ifdef X"));
+        assert!(position(&code, "// [pp] removed: #endif") < position(&code, "void a(void)"));
+    }
+
+    #[test]
+    fn only_moved_functions_count_as_reordered() {
+        let output = |source: &str| preprocess_named(source, Path::new("t.c"), &Options::default()).unwrap();
+        assert_eq!(output("int f(void) { return 1; }\n").stats.functions_reordered, 0);
+        let output = output("int a(void) { return b(); }\nint b(void) { return 1; }\nint c(void) { return 2; }\n");
+        assert_eq!(output.stats.functions_reordered, 2);
+    }
+
+    #[test]
+    fn globals_precede_the_functions_using_them() {
+        let code = run("void increment(void) { counter++; }\nstatic int counter;\n");
+        assert!(position(&code, "static int counter;") < position(&code, "void increment(void)"));
+    }
+
+    #[test]
+    fn only_moved_definitions_are_marked() {
+        let source = "struct S { int x; };\nint g;\nint f(void) { return g; }\n";
+        assert_eq!(run(source), source);
+        let code = run("int f(void) { return g; }\nint g;\n");
+        assert!(code.starts_with("// Global start: g\nint g;\n// Function start: f\n"));
+        assert_eq!(run(&code), code);
+    }
+
+    #[test]
+    fn include_guards_define_their_macro_and_inline_once() {
+        let dir = temp_dir("guards");
+        fs::write(dir.join("g.h"), "#ifndef G_H\n#define G_H\nint g;\n#endif\n").unwrap();
+        let source = "#include \"g.h\"\n#include \"g.h\"\n#ifdef G_H\nint guarded;\n#endif\n";
+        let output = run_in(&dir, source, &Options::default()).unwrap();
+        assert_eq!(output.code, "int g;\nint guarded;\n");
+        assert!(output.macros.contains_key("G_H"));
+
+        let defined = Options { macros: vec![("G_H".into(), Some(String::new()))], ..Options::default() };
+        assert_eq!(run_in(&dir, source, &defined).unwrap().code, "int guarded;\n");
+    }
+
+    #[test]
+    fn line_expands_to_the_original_line_number() {
+        let source = format!("{}printf(\"%d\", __LINE__);\n", "\n".repeat(41));
+        assert!(run(&source).lines().nth(41).is_some_and(|line| line == "printf(\"%d\", 42);"));
+    }
+
+    #[test]
+    fn predefined_macros_are_defined_for_conditionals() {
+        let source = "#ifdef __LINE__\nint line;\n#endif\n\
+                      #if defined(__FILE__) && defined __DATE__\nint file;\n#endif\n";
+        assert_eq!(run(source), "int line;\nint file;\n");
+        let bare = Options { no_predefined: true, ..Options::default() };
+        assert_eq!(preprocess(source, &bare).unwrap(), "");
+    }
+
+    #[test]
+    fn counter_increases_across_computed_includes_and_code() {
+        let dir = temp_dir("counter");
+        fs::write(dir.join("0.h"), "int zero;\n").unwrap();
+        let source = "#define STR(x) #x\n#define XSTR(x) STR(x)\n#include XSTR(__COUNTER__.h)\n\
+                      int a = __COUNTER__;\nint b = __COUNTER__;\n";
+        let code = run_in(&dir, source, &Options::default()).unwrap().code;
+        assert_eq!(code, "int zero;\nint a = 1;\nint b = 2;\n");
+    }
+
+    #[test]
+    fn unclosed_enum_is_not_a_panic() {
+        assert!(run("enum E { A, B\n").contains("enum E { A, B"));
+        assert!(run("int x;\nenum E { A, B\n").contains("enum E { A, B"));
+    }
+
+    #[test]
+    fn nested_includes_are_inlined_once() {
+        let dir = temp_dir("nested");
+        fs::write(dir.join("b.h"), "#include \"c.h\"\nint b;\n").unwrap();
+        fs::write(dir.join("c.h"), "#pragma once\nenum { MAX = 10 };\n").unwrap();
+        let source = "#include \"b.h\"\n#include \"c.h\"\nint a[MAX];\n";
+        let code = run_in(&dir, source, &Options::default()).unwrap().code;
+        assert_eq!(code, "enum { MAX = 10 };\nint b;\nint a[MAX];\n");
+        assert_eq!(code.matches("MAX = 10").count(), 1);
+    }
+
+    #[test]
+    fn circular_includes_name_the_whole_chain() {
+        let dir = temp_dir("circular");
+        fs::write(dir.join("a.h"), "#include \"b.h\"\n").unwrap();
+        fs::write(dir.join("b.h"), "#include \"a.h\"\n").unwrap();
+        let error = run_in(&dir, "#include \"a.h\"\n", &Options::default()).err().unwrap();
+        assert!(matches!(error, PreprocessError::CircularInclude(_)));
+        let message = error.to_string();
+        assert!(message.contains("a.h -> ") && message.contains("b.h -> "), "{}", message);
+    }
+
+    #[test]
+    fn false_elif_falls_through_to_else() {
+        let source = "#if 0\nint a;\n#elif 1 - 1\nint b;\n#else\nint c;\n#endif\n";
+        assert_eq!(run(source), "int c;\n");
+    }
+
+    #[test]
+    fn undef_ends_a_definition() {
+        let source = "#define DEBUG 1\nint a = DEBUG;\n#undef DEBUG\n\
+                      #ifdef DEBUG\nint debug;\n#endif\nint b = DEBUG;\n";
+        assert_eq!(run(source), "int a = 1;\nint b = DEBUG;\n");
+    }
+
+    #[test]
+    fn error_directives_fail_only_in_live_code() {
+        let error = preprocess_named("int a;\n#error \"unsupported\"\n", Path::new("e.c"), &Options::default());
+        let error = error.err().unwrap();
+        assert!(matches!(error, PreprocessError::ErrorDirective { .. }));
+        assert_eq!(error.to_string(), "e.c:2: #error \"unsupported\"");
+        assert_eq!(run("#ifdef NEVER\n#error \"unsupported\"\n#endif\nint a;\n"), "int a;\n");
+    }
+
+    /// The names of the functions found in `source`, in source order.
+    fn function_names(source: &str, opts: &Options) -> Vec<String> {
+        let output = preprocess_named(source, Path::new("f.c"), opts).unwrap();
+        output.functions.into_iter().map(|function| function.name).collect()
+    }
+
+    #[test]
+    fn literals_are_not_code() {
+        let source = "void f(void) {\n    printf(\"int main() {\");\n    puts(\"#define FAKE 1\");\n}\n";
+        assert_eq!(function_names(source, &Options::default()), ["f"]);
+        assert_eq!(run(source), source);
+    }
+
+    #[test]
+    fn callees_precede_callers() {
+        let code = run("void a(void) { b(); }\nvoid b(void) { }\n");
+        assert!(position(&code, "void b(void)") < position(&code, "void a(void)"));
+    }
+
+    #[test]
+    fn multi_line_bodies_move_whole() {
+        let f = "int f(int x) {\n    if (x) {\n        return '}';\n    }\n    return 0; /* } */\n}\n";
+        let code = run(&format!("int g(void) {{ return f(1); }}\n{}", f));
+        assert!(code.contains(f));
+        assert!(position(&code, f) < position(&code, "int g(void)"));
+    }
+
+    #[test]
+    fn mutual_recursion_gets_a_prototype() {
+        let code = run("int a(int n) { return n ? b(n - 1) : 0; }\nint b(int n) { return n ? a(n - 1) : 1; }\n");
+        assert!(code.lines().any(|line| line == "int a(int n);" || line == "int b(int n);"), "{}", code);
+        assert!(code.contains("int a(int n) {") && code.contains("int b(int n) {"));
+    }
+
+    #[test]
+    fn return_types_can_have_qualifiers_and_pointers() {
+        let source = "static int *foo(void) { return 0; }\nconst char* bar() { return \"\"; }\n\
+                      unsigned long baz() { return 0; }\nif (x) {\n}\nwhile (y) {\n}\n";
+        assert_eq!(function_names(source, &Options::default()), ["foo", "bar", "baz"]);
+    }
+
+    #[test]
+    fn conflicting_redefinitions_warn() {
+        let warnings = |source| preprocess_named(source, Path::new("r.c"), &Options::default()).unwrap().warnings;
+        assert_eq!(warnings("#define PI 3\n#define PI 4\n"), 1);
+        assert_eq!(warnings("#define PI 3\n#define PI  3\n"), 0);
+    }
+
+    #[test]
+    fn doc_comments_move_with_their_definition() {
+        let code = run("void g(void) { f(); }\n/** docs */\n// more\nvoid f(void) { }\n");
+        assert!(code.contains("/** docs */\n// more\nvoid f(void) { }\n"));
+        assert!(position(&code, "/** docs */") < position(&code, "void g(void)"));
+    }
+
+    #[test]
+    fn undefined_identifier_selects_else() {
+        assert_eq!(run("#if NOT_SET\nint a;\n#else\nint b;\n#endif\n"), "int b;\n");
+    }
+
+    #[test]
+    fn computed_includes_are_expanded() {
+        let dir = temp_dir("computed");
+        fs::write(dir.join("config.h"), "int config;\n").unwrap();
+        fs::write(dir.join("sys.h"), "int sys;\n").unwrap();
+        let include_dirs = vec![dir.clone()];
+        let opts = Options { include_dirs, inline_system: InlineSystem::Always, ..Options::default() };
+        let source = "#define HDR \"config.h\"\n#include HDR\n#define SYS <sys.h>\n#include SYS\n";
+        assert_eq!(run_in(&dir, source, &opts).unwrap().code, "int config;\nint sys;\n");
+    }
+
+    #[test]
+    fn has_include_looks_for_the_header() {
+        let dir = temp_dir("has-include");
+        fs::write(dir.join("present.h"), "").unwrap();
+        let opts = Options { include_dirs: vec![dir.clone()], ..Options::default() };
+        let source = "#if __has_include(<present.h>) && __has_include(\"present.h\")\nint present;\n#endif\n\
+                      #if __has_include(<missing.h>)\nint missing;\n#endif\n";
+        assert_eq!(run_in(&dir, source, &opts).unwrap().code, "int present;\n");
+    }
+
+    #[test]
+    fn crlf_input_keeps_crlf() {
+        let code = run("#define N 3\r\nint a = N;\r\nint b;\r\n");
+        assert_eq!(code, "int a = 3;\r\nint b;\r\n");
+        let lf = Options { line_endings: LineEndings::Lf, ..Options::default() };
+        assert_eq!(preprocess("int a;\r\nint b;\n", &lf).unwrap(), "int a;\nint b;\n");
+    }
+
+    #[test]
+    fn reordering_is_deterministic() {
+        let source = "int a(void) { return c() + d(); }\nint b(void) { return 2; }\nint c(void) { return 3; }\n\
+                      int d(void) { return 4; }\nint e(void) { return b(); }\n";
+        let code = run(source);
+        assert_eq!(run(source), code);
+        let order = ["int b(", "int c(", "int d(", "int a(", "int e("].map(|function| position(&code, function));
+        assert!(order.is_sorted(), "{}", code);
+    }
+
+    #[test]
+    fn pragmas_can_be_stripped_by_prefix() {
+        let opts = Options { strip_pragmas: vec!["omp".into()], ..Options::default() };
+        let source = "#pragma omp parallel for\n#pragma ompx keep\n#pragma pack(1)\n";
+        assert_eq!(preprocess(source, &opts).unwrap(), "#pragma ompx keep\n#pragma pack(1)\n");
+    }
+
+    #[test]
+    fn rust_consts_escape_their_values() {
+        let rust = Options { target: Target::Rust, ..Options::default() };
+        let code = preprocess("#define PATH \"C:\\\\tmp\\\"x\"\n#define N 0x10\n#define EXPR a + b\n", &rust);
+        let expected = "const PATH: &str = \"C:\\\\tmp\\\"x\";\nconst N: i64 = 0x10;\n\
+                        // EXPR has no Rust type: a + b\n";
+        assert_eq!(code.unwrap(), expected);
+    }
+
+    #[test]
+    fn c_target_drops_defines() {
+        let code = run("#define N 3\n#define NAME \"x\"\nint a[N];\nconst char *s = NAME;\n");
+        assert_eq!(code, "int a[3];\nconst char *s = \"x\";\n");
+    }
+
+    #[test]
+    fn unterminated_comments_and_strings_fail() {
+        let comment = preprocess_named("int a;\n  /* open\nint b;\n", Path::new("u.c"), &Options::default());
+        let location = |result: Result<Output, PreprocessError>| match result {
+            Err(PreprocessError::Unterminated { location, .. }) => location,
+            _ => panic!("not an unterminated literal or comment"),
+        };
+        assert_eq!(location(comment), "u.c:2:3");
+        let string = preprocess_named("char *s = \"abc;\n", Path::new("u.c"), &Options::default());
+        assert_eq!(location(string), "u.c:1:11");
+    }
+
+    #[test]
+    fn include_once_all_inlines_each_header_once() {
+        let dir = temp_dir("once-all");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("h.h"), "int h;\n").unwrap();
+        let source = "#include \"h.h\"\n#include \"sub/../h.h\"\n";
+        assert_eq!(run_in(&dir, source, &Options::default()).unwrap().code, "int h;\nint h;\n");
+        let once = Options { include_once_all: true, ..Options::default() };
+        assert_eq!(run_in(&dir, source, &once).unwrap().code, "int h;\n");
+    }
+
+    #[test]
+    fn unused_macros_warn() {
+        let dir = temp_dir("unused");
+        let opts = Options { warn_unused_macros: true, ..Options::default() };
+        let source = "#define UNUSED 1\n#define USED 2\n#define TESTED\nint x = USED;\n#ifdef TESTED\n#endif\n";
+        assert_eq!(run_in(&dir, source, &opts).unwrap().warnings, 1);
+    }
+
+    #[test]
+    fn include_next_continues_with_the_next_directory() {
+        let dir = temp_dir("include-next");
+        for (sub, text) in [("d1", "#include_next <x.h>\nint first;\n"), ("d2", "int second;\n")] {
+            fs::create_dir(dir.join(sub)).unwrap();
+            fs::write(dir.join(sub).join("x.h"), text).unwrap();
+        }
+        let include_dirs = vec![dir.join("d1"), dir.join("d2")];
+        let opts = Options { include_dirs, inline_system: InlineSystem::Found, ..Options::default() };
+        assert_eq!(run_in(&dir, "#include <x.h>\n", &opts).unwrap().code, "int second;\nint first;\n");
+    }
+
+    #[test]
+    fn macro_files_preload_definitions() {
+        let dir = temp_dir("define-from-file");
+        let defs = "#define PLATFORM 4\n#define TWICE(x) ((x) * 2)\n#define GONE\n#undef GONE\n";
+        fs::write(dir.join("defs.h"), defs).unwrap();
+        let macros = read_macro_file(&dir.join("defs.h"), &Options::default()).unwrap();
+        let opts = Options { macros, ..Options::default() };
+        let code = run_in(&dir, "#ifndef GONE\nint p = TWICE(PLATFORM);\n#endif\n", &opts).unwrap().code;
+        assert_eq!(code, "int p = ((4) * 2);\n");
+    }
+
+    #[test]
+    fn text_between_definitions_is_kept() {
+        let source = "  int f(void) {\n\treturn 1;\n  }\n\n\n// trailing\n   \n";
+        assert_eq!(run(source), source);
+    }
+
+    #[test]
+    fn standard_selects_its_branch() {
+        let source = "#if __STDC_VERSION__ >= 201112L\nint c11;\n#else\nint c99;\n#endif\n";
+        let with = |standard| Options { standard: Some(standard), ..Options::default() };
+        assert_eq!(preprocess(source, &with(Standard::C11)).unwrap(), "int c11;\n");
+        assert_eq!(preprocess(source, &with(Standard::C99)).unwrap(), "int c99;\n");
+    }
+
+    #[test]
+    fn hashes_inside_a_line_are_not_directives() {
+        let source = "int f(void) { x = a#b; __asm__(\"#define X\"); }\ny = 1 # define Z 2;\n";
+        assert_eq!(run(source), source);
+    }
+
+    #[test]
+    fn quoted_includes_resolve_against_the_including_file() {
+        // The test runs from the crate's directory, which has none of these files
+        let dir = temp_dir("relative");
+        fs::create_dir_all(dir.join("src/inc/sub")).unwrap();
+        fs::write(dir.join("src/inc/b.h"), "#include \"sub/c.h\"\nint b;\n").unwrap();
+        fs::write(dir.join("src/inc/sub/c.h"), "int c;\n").unwrap();
+        let output = preprocess_named("#include \"inc/b.h\"\n", &dir.join("src/a.c"), &Options::default());
+        assert_eq!(output.unwrap().code, "int c;\nint b;\n");
+    }
+
+    #[test]
+    fn stdin_resolves_includes_in_the_base_dir() {
+        let dir = temp_dir("stdin");
+        fs::write(dir.join("h.h"), "int h;\n").unwrap();
+        let opts = Options { base_dir: Some(dir), ..Options::default() };
+        let source = "#include \"h.h\"\nconst char *f = __FILE__;\n";
+        let output = preprocess_named(source, Path::new("<stdin>"), &opts);
+        assert_eq!(output.unwrap().code, "int h;\nconst char *f = \"<stdin>\";\n");
+    }
+
+    /// Where each of `names` is defined in `code`, as `int name(`, in order.
+    fn definition_order(code: &str, names: &[&str]) -> Vec<usize> {
+        names.iter().map(|name| position(code, &format!("int {}(", name))).collect()
+    }
+
+    #[test]
+    fn transitive_dependencies_order_the_whole_chain() {
+        let code = run("int a(void) { return b(); }\nint b(void) { return c(); }\nint c(void) { return 0; }\n");
+        assert!(definition_order(&code, &["c", "b", "a"]).is_sorted(), "{}", code);
+    }
+
+    #[test]
+    fn independent_functions_keep_their_place() {
+        let source = "int a(void) { return b(); }\nint b(void) { return c(); }\nint c(void) { return 0; }\n\
+                      int d(void) { return 1; }\n";
+        let code = run(source);
+        assert!(definition_order(&code, &["c", "b", "a", "d"]).is_sorted(), "{}", code);
+        assert!(code.ends_with("int d(void) { return 1; }\n"));
+    }
+
+    #[test]
+    fn files_with_few_functions_are_unchanged() {
+        assert_eq!(run(""), "");
+        let comments = "// only a comment\n/* and\n   another */\n";
+        assert_eq!(run(comments), comments);
+        assert_eq!(run("int main(void) {\n    return 0;\n}\n"), "int main(void) {\n    return 0;\n}\n");
+    }
+
+    #[test]
+    fn if_zero_drops_everything_inside() {
+        let source = "#if 0\nint broken = (;\n#if UNDEFINED(\n#endif\n#error no\n#endif\nint ok;\n";
+        assert_eq!(run(source), "int ok;\n");
+    }
+
+    #[test]
+    fn prototypes_stay_in_place() {
+        let source = "int foo(int);\nint bar(void) { return foo(1); }\nint foo(int x) { return x; }\n";
+        assert_eq!(function_names(source, &Options::default()), ["bar", "foo"]);
+        let code = run(source);
+        assert!(code.starts_with("int foo(int);\n"));
+        assert!(position(&code, "int foo(int x)") < position(&code, "int bar(void)"));
+    }
+
+    #[test]
+    fn byte_order_marks_are_stripped() {
+        let dir = temp_dir("bom");
+        fs::write(dir.join("bom.h"), "\u{feff}#pragma once\nint bom;\n").unwrap();
+        let source = "\u{feff}#include \"bom.h\"\n#include \"bom.h\"\n";
+        let code = run_in(&dir, source, &Options::default()).unwrap().code;
+        assert_eq!(code, "int bom;\n");
+    }
+
+    #[test]
+    fn keywords_and_macros_add_no_dependencies() {
+        let opts = Options { dependency_graph: true, ..Options::default() };
+        let source = "#define SIZE 4\n#define STATE_A 1\nint f(int x) {\n    int a[SIZE];\n\
+                      if(x) { while(x) x--; }\n    switch (x) { case STATE_A: break; }\n    return SIZE;\n}\n\
+                      int g(void) { return 1; }\n";
+        let graph = preprocess_named(source, Path::new("g.c"), &opts).unwrap().dependency_graph.unwrap();
+        assert!(!graph.contains("->"), "{}", graph);
+    }
+
+    #[test]
+    fn empty_defines_expand_to_nothing() {
+        assert_eq!(run("#define FLAG\n#ifdef FLAG\nFLAG;\n#endif\n"), ";\n");
+    }
+
+    #[test]
+    fn writer_output_matches_the_string_api() {
+        let source = "#define N 2\nint a(void) { return b(); }\nint b(void) { return N; }\n";
+        let mut out = Vec::new();
+        preprocess_to_writer(source, &Options::default(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), run(source));
+    }
+
+    #[test]
+    fn system_includes_follow_inline_system() {
+        let dir = temp_dir("inline-system");
+        fs::write(dir.join("stdio.h"), "int from_stdio;\n").unwrap();
+        let with = |inline_system| {
+            Options { include_dirs: vec![dir.clone()], inline_system, ..Options::default() }
+        };
+        let source = "#include <stdio.h>\n#include <missing.h>\n";
+        let never = run_in(&dir, source, &with(InlineSystem::Never)).unwrap().code;
+        assert_eq!(never, source);
+        let found = run_in(&dir, source, &with(InlineSystem::Found)).unwrap().code;
+        assert_eq!(found, "int from_stdio;\n#include <missing.h>\n");
+        let always = run_in(&dir, source, &with(InlineSystem::Always));
+        assert!(matches!(always, Err(PreprocessError::IncludeNotFound { ref path, .. }) if path == "missing.h"));
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported_or_decoded() {
+        let dir = temp_dir("utf8");
+        fs::write(dir.join("bad.h"), b"char c = '\xe9';\n").unwrap();
+        let source = "#include \"bad.h\"\n";
+        let error = run_in(&dir, source, &Options::default()).err().unwrap();
+        assert!(matches!(error, PreprocessError::InvalidUtf8 { offset: 10, .. }), "{}", error);
+        let latin1 = Options { encoding: Encoding::Latin1, ..Options::default() };
+        assert_eq!(run_in(&dir, source, &latin1).unwrap().code, "char c = '\u{e9}';\n");
+    }
+
+    #[test]
+    fn directives_allow_spaces_and_null_directives_vanish() {
+        let source = "#   define   SPACED   1\n# ifdef SPACED\nint s = SPACED;\n  #  endif\n#\n  #  \n";
+        assert_eq!(run(source), "int s = 1;\n");
+    }
+
+    #[test]
+    fn blank_lines_collapse_or_stay() {
+        let source = "int a;\n\n\n\nint b;\n";
+        assert_eq!(run(source), source);
+        let collapse = Options { blank_lines: BlankLines::Collapse, ..Options::default() };
+        assert_eq!(preprocess(source, &collapse).unwrap(), "int a;\n\nint b;\n");
+    }
+
+    #[test]
+    fn function_pattern_can_be_replaced() {
+        let source = "int g(void) { return f(); }\n__attribute__((noinline)) int f(void) { return 1; }\n";
+        assert_eq!(function_names(source, &Options::default()), ["g"]);
+        let pattern = r"^\s*(?:__attribute__\(\([^)]*\)\)\s*)?((?:[A-Za-z_]\w*[\s*]+)+)(\w+)\s*\(([^;]*)\)\s*\{";
+        let opts = Options { function_pattern: Some(Regex::new(pattern).unwrap()), ..Options::default() };
+        assert_eq!(function_names(source, &opts), ["g", "f"]);
+        let code = preprocess(source, &opts).unwrap();
+        assert!(position(&code, "int f(void)") < position(&code, "int g(void)"));
+    }
+
+    #[test]
+    fn sysroot_holds_system_headers() {
+        let dir = temp_dir("sysroot");
+        fs::create_dir_all(dir.join("usr/include")).unwrap();
+        fs::write(dir.join("usr/include/sys.h"), "int sys;\n").unwrap();
+        let sysroot = Some(dir.clone());
+        let opts = Options { sysroot, inline_system: InlineSystem::Always, ..Options::default() };
+        let output = run_in(&dir, "#include <sys.h>\n", &opts).unwrap();
+        assert_eq!(output.code, "int sys;\n");
+        assert_eq!(output.system_headers, [dir.join("usr/include/sys.h")]);
+    }
+
+    #[test]
+    fn include_dirs_come_before_system_dirs() {
+        let dir = temp_dir("isystem");
+        for (sub, text) in [("inc", "int from_i;\n"), ("sys", "int from_isystem;\n")] {
+            fs::create_dir(dir.join(sub)).unwrap();
+            fs::write(dir.join(sub).join("both.h"), text).unwrap();
+        }
+        fs::write(dir.join("sys/only.h"), "int only;\n").unwrap();
+        let opts = Options {
+            include_dirs: vec![dir.join("inc")],
+            system_include_dirs: vec![dir.join("sys")],
+            ..Options::default()
+        };
+        let output = run_in(&dir, "#include \"both.h\"\n#include \"only.h\"\n", &opts).unwrap();
+        assert_eq!(output.code, "int from_i;\nint only;\n");
+        assert_eq!(output.system_headers, [dir.join("sys/only.h")]);
+    }
+
+    #[test]
+    fn initializer_braces_end_with_their_declaration() {
+        let source = "int a[] = {1, {2}};\nint f(void) {\n    return a[0];\n}\n";
+        let output = preprocess_named(source, Path::new("i.c"), &Options::default()).unwrap();
+        assert_eq!(output.code, source);
+        assert_eq!(output.functions.iter().map(|function| function.line).collect::<Vec<_>>(), [2]);
+        let code = run("int f(void) { return a[0]; }\nint a[] = {1, {2}};\n");
+        assert!(code.starts_with("// Global start: a\nint a[] = {1, {2}};\n"), "{}", code);
+    }
+
+    #[test]
+    fn dead_branches_include_nothing() {
+        let dir = temp_dir("dead-include");
+        fs::write(dir.join("never.h"), "#define NEVER_MACRO 1\nint never;\n").unwrap();
+        let source = "#ifdef NEVER\n#include \"never.h\"\n#include \"missing.h\"\n#endif\nint x;\n";
+        let output = run_in(&dir, source, &Options::default()).unwrap();
+        assert_eq!(output.code, "int x;\n");
+        assert!(output.headers.is_empty() && !output.macros.contains_key("NEVER_MACRO"));
+    }
+
+    #[test]
+    fn no_predefined_leaves_builtin_names_alone() {
+        let macros = vec![("ONE".into(), Some("1".into()))];
+        let opts = Options { no_predefined: true, macros, ..Options::default() };
+        let source = "int l = __LINE__ + ONE;\nconst char *f = __FILE__;\n";
+        assert_eq!(preprocess(source, &opts).unwrap(), source.replace("ONE", "1"));
+    }
+
+    #[test]
+    fn comments_are_not_part_of_a_macro_body() {
+        let source = "#define X 1 // one\n#define Y 2 /* two */\n#define F(a) ((a) /* f */ + 1)\n\
+                      #if X + Y == 3\nint a = X + Y + F(3);\n#endif\n";
+        let output = preprocess_named(source, Path::new("c.c"), &Options::default()).unwrap();
+        assert_eq!(output.code, "int a = 1 + 2 + ((3)   + 1);\n");
+        assert_eq!(output.macros["X"].body, "1");
+        assert_eq!(output.macros["Y"].body, "2");
+    }
+
+    #[test]
+    fn comments_after_conditions_are_ignored() {
+        let dir = temp_dir("conditional-comments");
+        fs::write(dir.join("off.h"), "int off;\n").unwrap();
+        let source = "#define A\n#if 1 // yes\nint a;\n#endif\n#if 1 /* c */\nint b;\n#endif\n\
+                      #if defined(A) // A\nint c;\n#elif 1 /* no */\nint d;\n#endif\n\
+                      #if 0 // off\n#include \"off.h\"\n#endif\n#ifdef A // A\nint e;\n#endif\n";
+        let output = run_in(&dir, source, &Options::default()).unwrap();
+        assert_eq!(output.code, "int a;\nint b;\nint c;\nint e;\n");
+        assert!(output.headers.is_empty());
+    }
+
+    #[test]
+    fn commented_out_directives_are_ignored() {
+        assert_eq!(run("/*\n#if 0\n*/\nint a;\n"), "/*\n#if 0\n*/\nint a;\n");
+        let source = "/* #define FOO 1\n#define FOO 1 */\n#ifdef FOO\nint foo = FOO;\n#endif\nint b;\n";
+        assert_eq!(run(source), "/* #define FOO 1\n#define FOO 1 */\nint b;\n");
+    }
+
+    #[test]
+    fn forward_declarations_stay_and_function_pointers_are_globals() {
+        let source = "struct node;\nstruct node { struct node *next; };\n";
+        assert_eq!(run(source), source);
+        let code = run("void run(void) { handler(); }\nstatic void (*handler)(void) = 0;\n");
+        assert!(position(&code, "static void (*handler)") < position(&code, "void run("), "{}", code);
+        assert!(code.contains("// Global start: handler\n"), "{}", code);
+    }
+
+    #[test]
+    fn signatures_may_span_several_lines() {
+        let g = "static const char *\ng(int a,\n  int b)\n{\n    return 0;\n}\n";
+        let source = format!("int f(void) {{ return g(1, 2) != 0; }}\n{}", g);
+        let output = preprocess_named(&source, Path::new("s.c"), &Options::default()).unwrap();
+        let functions = output.functions.iter().map(|function| (function.name.as_str(), function.line));
+        assert_eq!(functions.collect::<Vec<_>>(), [("f", 1), ("g", 2)]);
+        assert_eq!(output.functions[1].return_type, "static const char *");
+        assert_eq!(output.functions[1].params, ["int a", "int b"]);
+        assert!(position(&output.code, g) < position(&output.code, "int f(void)"), "{}", output.code);
+    }
+
+    #[test]
+    fn duplicate_functions_warn() {
+        let source = "int f(void) { return 1; }\nint g(void) { return f(); }\nint f(void) { return 2; }\n";
+        let output = preprocess_named(source, Path::new("d.c"), &Options::default()).unwrap();
+        assert_eq!(output.warnings, 1);
+        assert_eq!(output.functions.iter().filter(|function| function.name == "f").count(), 2);
+        assert!(output.code.starts_with("int f(void) { return 1; }\n"), "{}", output.code);
+    }
+
+    #[test]
+    fn decoded_text_follows_the_encoding() {
+        let stdin = Path::new("<stdin>");
+        let error = decode_text(b"int a;\nchar c = '\xe9';\n".to_vec(), Encoding::Utf8, stdin).err().unwrap();
+        assert_eq!(error.to_string(), "'<stdin>' is not valid UTF-8: invalid byte at offset 17");
+        let text = decode_text(b"char c = '\xe9';\n".to_vec(), Encoding::Latin1, stdin).unwrap();
+        assert_eq!(text, "char c = '\u{e9}';\n");
+    }
+
+    #[test]
+    fn macro_arguments_may_span_lines() {
+        let source = "#define SQ(x) ((x)*(x))\n#define MUL(a, b) ((a)*(b))\nint a = SQ(\n    3);\n\
+                      int b = MUL(SQ(1),\n2) + SQ(4);\nint c;\n";
+        assert_eq!(run(source), "int a = ((3)*(3));\nint b = ((((1)*(1)))*(2)) + ((4)*(4));\nint c;\n");
+        let unclosed = preprocess_named("#define SQ(x) x\nint a = SQ(3;\n", Path::new("u.c"), &Options::default());
+        let error = unclosed.err().unwrap();
+        assert_eq!(error.to_string(), "u.c:2: the arguments of macro 'SQ' are never closed");
+    }
+}
@@ -0,0 +1,622 @@
+//! Expansion of macro uses in the body lines of a source file.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::tokenizer::{self, Kind, Token};
+use crate::{Options, Verbosity};
+
+/// A `#define`d macro.
+pub struct Macro {
+    /// Parameter names of a function-like macro, or `None` for an object-like one.
+    pub params: Option<Vec<String>>,
+    /// Replacement text.
+    pub body: String,
+    /// Where the macro was defined: the `file:line` of its `#define`, or `<built-in>` or
+    /// `<command line>`.
+    pub location: String,
+}
+
+impl Macro {
+    /// Whether `other` defines the macro the same way, ignoring differences in whitespace.
+    pub fn same_definition(&self, other: &Macro) -> bool {
+        self.params == other.params && self.body.split_whitespace().eq(other.body.split_whitespace())
+    }
+
+    /// The definition as it follows the macro's name in a `#define`, e.g. `(a, b) a + b`.
+    pub fn definition(&self) -> String {
+        match &self.params {
+            Some(params) => format!("({}) {}", params.join(", "), self.body),
+            None => self.body.clone(),
+        }
+    }
+}
+
+/// The predefined macros a fresh table starts with. `__FILE__` and `__LINE__` are
+/// placeholders that the caller updates for every line it expands; `__DATE__` and `__TIME__`
+/// give the current UTC date and time in the formats C uses, e.g. `"Jan  6 2025"` and
+/// `"09:05:00"`. `__COUNTER__` is a placeholder too, replaced by [`expand_line`] with the
+/// counter it is given.
+pub fn predefined() -> HashMap<String, Macro> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let months = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let date = format!("{} {:2} {}", months[month as usize - 1], day, year);
+    let time = format!("{:02}:{:02}:{:02}", secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    [
+        ("__FILE__", String::from("\"\"")),
+        ("__LINE__", String::from("0")),
+        ("__COUNTER__", String::from("0")),
+        ("__DATE__", string_literal(&date)),
+        ("__TIME__", string_literal(&time)),
+    ]
+    .into_iter()
+    .map(|(name, body)| (name.to_string(), Macro { params: None, body, location: "<built-in>".into() }))
+    .collect()
+}
+
+/// Converts a count of days since 1970-01-01 into a (year, month, day) date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// Quotes `text` as a C string literal, escaping `"` and `\`.
+pub fn string_literal(text: &str) -> String {
+    let mut literal = String::with_capacity(text.len() + 2);
+    literal.push('"');
+    for c in text.chars() {
+        if c == '"' || c == '\\' {
+            literal.push('\\');
+        }
+        literal.push(c);
+    }
+    literal.push('"');
+    literal
+}
+
+/// The options naming the macros whose expansions are reported on stderr, step by step, and
+/// where the line being expanded was read from.
+pub struct Trace<'a> {
+    /// The options whose [`Options::trace_macros`] are reported on, usually none.
+    pub opts: &'a Options,
+    /// The `file:line` each report starts with; unused when no macros are traced.
+    pub location: String,
+}
+
+impl Trace<'_> {
+    fn traces(&self, name: &str) -> bool {
+        self.opts.trace_macros.iter().any(|traced| traced == name)
+    }
+
+    fn report(&self, name: &str, message: fmt::Arguments) {
+        let message = message.to_string().replace(PAINTED, "");
+        crate::log(self.opts, Verbosity::Normal, format_args!("{}: trace: {}: {}", self.location, name, message));
+    }
+}
+
+/// Marks an identifier that must never be expanded, because it names a macro whose own
+/// replacement it came out of; as in cpp, it stays "painted blue" wherever that text ends up,
+/// even once it is rescanned as part of another macro's replacement. The marks are removed
+/// from each line once it is expanded.
+const PAINTED: char = '\u{1}';
+
+/// How many macro expansions a single line may take before expansion is abandoned. The rule
+/// against re-expanding a macro inside its own replacement already stops recursion, so this
+/// only guards against definitions whose expansion grows exponentially.
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// Replaces every use of a macro in `line` with its replacement text.
+///
+/// Only whole identifiers are replaced, so `BUFSIZE2` is untouched by `BUFSIZE`, and nothing
+/// inside string or character literals or comments is substituted. A function-like macro is
+/// only expanded when its name is followed by a parenthesized argument list, which must be
+/// closed on the line, as [`ends_in_call`] lets the caller make sure of first, and hold as
+/// many arguments as it has parameters, or at least as many as it has before a trailing
+/// `...`, whose arguments `__VA_ARGS__` stands for; each parameter in the
+/// body is then replaced by the matching argument, with the macros in the argument expanded
+/// first unless the parameter is an operand of `#` or `##`. The replacement text is rescanned for
+/// further macro uses, during which the macro being expanded is not expanded again; a
+/// function-like macro name it ends with is expanded with the arguments that follow the use.
+/// A use of a macro found while it is disabled this way is never expanded, so `#define x (4 +
+/// y)` and `#define y (2 * x)` make `x` expand to `(4 + (2 * x))`, even through `ID(x)`.
+/// `in_comment` says whether a `/* ... */` comment is open at the start of the line and is
+/// updated for the next one. The name of every macro expanded is added to `used`, and each
+/// expansion of a macro in `trace` is reported: the use, its arguments, the substituted body
+/// and the result of rescanning it. Each expansion is counted in `expansions`, by macro name.
+/// Each use of the predefined `__COUNTER__` expands to the value of `counter`, which is then
+/// incremented, so the uses in a run number 0, 1, 2 and so on as long as the caller passes the
+/// same counter for every line. Fails if a function-like macro is given the wrong number of
+/// arguments or an argument list that is never closed, or if the line needs more than
+/// [`MAX_EXPANSIONS`] expansions.
+pub fn expand_line(
+    line: &str,
+    macros: &HashMap<String, Macro>,
+    in_comment: &mut bool,
+    used: &mut HashSet<String>,
+    trace: &Trace,
+    expansions: &mut BTreeMap<String, usize>,
+    counter: &mut usize,
+) -> Result<String, String> {
+    let mut scan = Scan { disabled: Vec::new(), expansions: 0, counts: expansions, counter };
+    expand(line, macros, in_comment, used, trace, &mut scan).map(|text| text.replace(PAINTED, ""))
+}
+
+/// Whether `line` ends inside the argument list of a use of one of the function-like
+/// `macros`, so that the list goes on on the next line, as in `SQ(` followed by `3)`.
+/// `in_comment` says whether a `/* ... */` comment is open at the start of the line.
+pub fn ends_in_call(line: &str, macros: &HashMap<String, Macro>, mut in_comment: bool) -> bool {
+    let function_like = |name| macros.get(name).is_some_and(|definition: &Macro| definition.params.is_some());
+    let mut rest = line;
+    while !rest.is_empty() {
+        let token = tokenizer::next_token(rest, &mut in_comment);
+        rest = &rest[token.text.len()..];
+        let unclosed_list = rest.trim_start().starts_with('(') && split_args(rest).is_none();
+        if token.kind == Kind::Ident && function_like(token.text) && unclosed_list {
+            return true;
+        }
+    }
+    false
+}
+
+/// What [`expand`] keeps track of while expanding a line.
+struct Scan<'a> {
+    /// The macros whose replacement is being rescanned.
+    disabled: Vec<String>,
+    /// The expansions done for the line so far.
+    expansions: usize,
+    /// The expansions done of each macro, including those of earlier lines.
+    counts: &'a mut BTreeMap<String, usize>,
+    /// The value the next use of `__COUNTER__` expands to.
+    counter: &'a mut usize,
+}
+
+/// Worker for [`expand_line`].
+fn expand(
+    line: &str,
+    macros: &HashMap<String, Macro>,
+    in_comment: &mut bool,
+    used: &mut HashSet<String>,
+    trace: &Trace,
+    scan: &mut Scan,
+) -> Result<String, String> {
+    let mut output = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while !rest.is_empty() {
+        let token = next_token(rest, in_comment);
+        let len = match token.kind {
+            Kind::Ident => {
+                let name = token.text;
+                rest = &rest[name.len()..];
+                if scan.disabled.iter().any(|active| active == name) {
+                    output.push(PAINTED);
+                    output.push_str(name);
+                    continue;
+                }
+                let traced = trace.traces(name);
+                let replacement = match macros.get(name) {
+                    Some(Macro { location, .. }) if name == "__COUNTER__" && location == "<built-in>" => {
+                        if traced {
+                            trace.report(name, format_args!("expanding '{}'", name));
+                        }
+                        *scan.counter += 1;
+                        Some((*scan.counter - 1).to_string())
+                    }
+                    Some(Macro { params: None, body, .. }) => {
+                        if traced {
+                            trace.report(name, format_args!("expanding '{}'", name));
+                        }
+                        Some(body.clone())
+                    }
+                    Some(Macro { params: Some(params), body, .. }) => {
+                        let call = match split_args(rest) {
+                            Some((args, len)) => {
+                                // `F()` passes one empty argument unless `F` takes none
+                                let given = if params.is_empty() { args.len() } else { args.len().max(1) };
+                                let bound = bind_args(params, args)
+                                    .ok_or_else(|| argument_count_error(name, params, given))?;
+                                Some((bound, len))
+                            }
+                            // Rather than leave a call behind that no compiler could read
+                            None if rest.trim_start().starts_with('(') => {
+                                return Err(format!("the arguments of macro '{}' are never closed", name));
+                            }
+                            None => None,
+                        };
+                        match call {
+                            Some((args, consumed)) => {
+                                if traced {
+                                    let message = format_args!("expanding '{}{}'", name, rest[..consumed].trim());
+                                    trace.report(name, message);
+                                }
+                                rest = &rest[consumed..];
+                                let expanded = args
+                                    .iter()
+                                    .map(|arg| expand(arg, macros, &mut false, used, trace, scan))
+                                    .collect::<Result<Vec<_>, _>>()?;
+                                if traced {
+                                    for (param, (arg, expanded)) in params.iter().zip(args.iter().zip(&expanded)) {
+                                        let message = format_args!("{} is '{}'", param, arg);
+                                        trace.report(name, format_args!("{}, expanded '{}'", message, expanded));
+                                    }
+                                }
+                                Some(substitute(body, params, &args, &expanded))
+                            }
+                            None => None,
+                        }
+                    }
+                    None => None,
+                };
+                match replacement {
+                    Some(replacement) => {
+                        scan.expansions += 1;
+                        if scan.expansions > MAX_EXPANSIONS {
+                            return Err(format!("expanding '{}' takes more than {} steps", name, MAX_EXPANSIONS));
+                        }
+                        *scan.counts.entry(name.to_string()).or_default() += 1;
+                        if !used.contains(name) {
+                            used.insert(name.to_string());
+                        }
+                        if traced {
+                            trace.report(name, format_args!("substituted '{}'", replacement));
+                        }
+                        scan.disabled.push(name.to_string());
+                        let rescanned = expand(&replacement, macros, &mut false, used, trace, scan)?;
+                        scan.disabled.pop();
+                        if traced {
+                            trace.report(name, format_args!("rescanned to '{}'", rescanned));
+                        }
+                        // A function-like macro named at the very end of the replacement takes
+                        // its arguments from the text after the use, as in `ID(F)(x)`
+                        let tail = trailing_ident(&rescanned).filter(|&tail| {
+                            tail != name
+                                && macros.get(tail).is_some_and(|tail| tail.params.is_some())
+                                && !scan.disabled.iter().any(|active| active == tail)
+                                && rest.trim_start().starts_with('(')
+                        });
+                        if let Some(tail) = tail {
+                            output.push_str(&rescanned[..rescanned.trim_end().len() - tail.len()]);
+                            let remainder = format!("{}{}", tail, rest);
+                            let expanded = expand(&remainder, macros, in_comment, used, trace, scan);
+                            output.push_str(&expanded?);
+                            return Ok(output);
+                        }
+                        output.push_str(&rescanned);
+                    }
+                    None => output.push_str(name),
+                }
+                continue;
+            }
+            _ => token.text.len(),
+        };
+        output.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    Ok(output)
+}
+
+/// The identifier `text` ends with, ignoring trailing whitespace, if it ends with one that
+/// isn't [`PAINTED`].
+fn trailing_ident(text: &str) -> Option<&str> {
+    let text = text.trim_end();
+    let start = text.char_indices().rev().take_while(|&(_, c)| c.is_alphanumeric() || c == '_').last()?.0;
+    let ident = &text[start..];
+    let painted = text[..start].ends_with(PAINTED);
+    (!painted && ident.starts_with(|c: char| c.is_alphabetic() || c == '_')).then_some(ident)
+}
+
+/// Reads the token at the start of `text` with [`tokenizer::next_token`], except that an
+/// identifier marked [`PAINTED`] is read, mark and all, as a [`Kind::Punct`], so that it is
+/// never substituted.
+fn next_token<'a>(text: &'a str, in_comment: &mut bool) -> Token<'a> {
+    match text.strip_prefix(PAINTED) {
+        Some(ident) if !*in_comment => {
+            let len = ident.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(ident.len());
+            Token { kind: Kind::Punct, text: &text[..PAINTED.len_utf8() + len] }
+        }
+        _ => tokenizer::next_token(text, in_comment),
+    }
+}
+
+/// Splits the parenthesized argument list at the start of `text` (after optional whitespace)
+/// on its top-level commas. Commas nested inside parentheses or literals don't split, and an
+/// empty list `()` yields no arguments. Returns the trimmed arguments and the number of bytes
+/// consumed through the closing parenthesis, or `None` if there is no complete list.
+fn split_args(text: &str) -> Option<(Vec<&str>, usize)> {
+    let open = text.len() - text.trim_start().len();
+    if !text[open..].starts_with('(') {
+        return None;
+    }
+
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut arg_start = open + 1;
+    let mut pos = open;
+    while pos < text.len() {
+        let c = text[pos..].chars().next().unwrap();
+        match c {
+            '"' | '\'' => {
+                pos += tokenizer::literal_len(&text[pos..], c).unwrap_or(text.len() - pos);
+                continue;
+            }
+            '(' => depth += 1,
+            ',' if depth == 1 => {
+                args.push(text[arg_start..pos].trim());
+                arg_start = pos + 1;
+            }
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    let last = text[arg_start..pos].trim();
+                    if !(args.is_empty() && last.is_empty()) {
+                        args.push(last);
+                    }
+                    return Some((args, pos + 1));
+                }
+            }
+            _ => {}
+        }
+        pos += c.len_utf8();
+    }
+    None
+}
+
+/// Matches the arguments `args` of a use of a function-like macro to its `params`, one per
+/// parameter. `F()` passes one empty argument, and a trailing `...` parameter takes all the
+/// remaining arguments, joined by commas, or none at all. Returns `None` if the number of
+/// arguments doesn't fit.
+fn bind_args(params: &[String], args: Vec<&str>) -> Option<Vec<String>> {
+    let mut bound: Vec<String> = args.into_iter().map(String::from).collect();
+    if bound.is_empty() && !params.is_empty() {
+        bound.push(String::new());
+    }
+    let fixed = params.len().saturating_sub(1);
+    if params.last().is_some_and(|param| param == "...") && bound.len() >= fixed {
+        let variadic = bound.split_off(fixed).join(", ");
+        bound.push(variadic);
+    }
+    (bound.len() == params.len()).then_some(bound)
+}
+
+/// The message for a use of the function-like macro `name` with `given` arguments, which
+/// doesn't fit its `params`.
+fn argument_count_error(name: &str, params: &[String], given: usize) -> String {
+    let expected = match params.last() {
+        Some(last) if last == "..." => format!("at least {}", params.len() - 1),
+        _ => params.len().to_string(),
+    };
+    format!("macro '{}' takes {} argument(s) but was given {}", name, expected, given)
+}
+
+/// The position in `params` of the parameter that `name` refers to in a macro body, where
+/// `__VA_ARGS__` refers to a trailing `...`.
+fn param_index(params: &[String], name: &str) -> Option<usize> {
+    params.iter().position(|param| param == name || (param == "..." && name == "__VA_ARGS__"))
+}
+
+/// Replaces each parameter name in `body` with the corresponding argument from `expanded`,
+/// where the arguments have had their own macros expanded, and each `#param` with the
+/// argument as written as a string literal. `a ## b` pastes the text on either side together
+/// by dropping the operator and the whitespace around it; a parameter on either side of it
+/// is replaced by the argument as written, as with `#`. As in GCC, `, ## __VA_ARGS__` drops
+/// the comma when no variadic arguments are passed. An argument as written loses any
+/// [`PAINTED`] marks, as the tokens `#` and `##` make are new ones.
+fn substitute(body: &str, params: &[String], args: &[String], expanded: &[String]) -> String {
+    let args: Vec<String> = args.iter().map(|arg| arg.replace(PAINTED, "")).collect();
+    let mut output = String::with_capacity(body.len());
+    let mut rest = body;
+    let mut after_paste = false;
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("##") {
+            output.truncate(output.trim_end().len());
+            rest = after.trim_start();
+            after_paste = true;
+            let operand = (!rest.is_empty()).then(|| next_token(rest, &mut false));
+            if let Some(Token { kind: Kind::Ident, text: name }) = operand {
+                let idx = (name == "__VA_ARGS__").then(|| param_index(params, "__VA_ARGS__")).flatten();
+                if idx.is_some_and(|idx| args[idx].is_empty()) && output.ends_with(',') {
+                    output.pop();
+                }
+            }
+            continue;
+        }
+        if rest.starts_with('#') {
+            let operand = rest[1..].trim_start();
+            let token = (!operand.is_empty()).then(|| next_token(operand, &mut false));
+            if let Some(Token { kind: Kind::Ident, text: name }) = token {
+                if let Some(idx) = param_index(params, name) {
+                    output.push_str(&stringize(&args[idx]));
+                    rest = &operand[name.len()..];
+                    continue;
+                }
+            }
+        }
+
+        let token = next_token(rest, &mut false);
+        let len = match token.kind {
+            Kind::Ident => {
+                let len = token.text.len();
+                match param_index(params, &rest[..len]) {
+                    Some(idx) if after_paste || rest[len..].trim_start().starts_with("##") => {
+                        output.push_str(&args[idx])
+                    }
+                    Some(idx) => output.push_str(&expanded[idx]),
+                    None => output.push_str(&rest[..len]),
+                }
+                rest = &rest[len..];
+                after_paste = false;
+                continue;
+            }
+            _ => token.text.len(),
+        };
+        output.push_str(&rest[..len]);
+        rest = &rest[len..];
+        after_paste = false;
+    }
+
+    output
+}
+
+/// Turns a macro argument into a string literal the way the `#` operator does: runs of
+/// whitespace between tokens become a single space, and every `"` and `\` (which can only
+/// come from string and character literals in the argument) is escaped.
+fn stringize(arg: &str) -> String {
+    let mut output = String::from("\"");
+    let mut rest = arg.trim();
+
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_whitespace() {
+            output.push(' ');
+            rest.len() - rest.trim_start().len()
+        } else {
+            let len = next_token(rest, &mut false).text.len();
+            for c in rest[..len].chars() {
+                if c == '"' || c == '\\' {
+                    output.push('\\');
+                }
+                output.push(c);
+            }
+            len
+        };
+        rest = &rest[len..];
+    }
+
+    output.push('"');
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The predefined macros plus `defines`, each a name, with a parameter list for a
+    /// function-like macro, and a body.
+    fn table(defines: &[(&str, &str)]) -> HashMap<String, Macro> {
+        let mut macros = predefined();
+        for (name, body) in defines {
+            let (name, params) = match name.split_once('(') {
+                Some((name, params)) => {
+                    let params = params.trim_end_matches(')').split(',').map(str::trim);
+                    (name, Some(params.filter(|param| !param.is_empty()).map(String::from).collect()))
+                }
+                None => (*name, None),
+            };
+            macros.insert(name.to_string(), Macro { params, body: body.to_string(), location: "test".into() });
+        }
+        macros
+    }
+
+    /// Expands `line` with the macros of [`table`].
+    fn expand_with(defines: &[(&str, &str)], line: &str) -> Result<String, String> {
+        let opts = Options::default();
+        let trace = Trace { opts: &opts, location: String::new() };
+        let macros = table(defines);
+        expand_line(line, &macros, &mut false, &mut HashSet::new(), &trace, &mut BTreeMap::new(), &mut 0)
+    }
+
+    #[test]
+    fn function_like_macros_take_arguments() {
+        let max = [("MAX(a, b)", "((a)>(b)?(a):(b))")];
+        assert_eq!(expand_with(&max, "MAX(x, y+1)").unwrap(), "((x)>(y+1)?(x):(y+1))");
+        assert_eq!(expand_with(&max, "MAX(f(1, 2), (3, 4))").unwrap(), "((f(1, 2))>((3, 4))?(f(1, 2)):((3, 4)))");
+        assert_eq!(expand_with(&[("PAIR(a, b)", "[a|b]")], "PAIR(, )").unwrap(), "[|]");
+        assert_eq!(expand_with(&max, "MAX + 1").unwrap(), "MAX + 1");
+    }
+
+    #[test]
+    fn stringizing_quotes_the_argument() {
+        let str = [("STR(x)", "#x")];
+        assert_eq!(expand_with(&str, "STR(hello)").unwrap(), "\"hello\"");
+        assert_eq!(expand_with(&str, "STR(a    b)").unwrap(), "\"a b\"");
+        assert_eq!(expand_with(&str, r#"STR("q")"#).unwrap(), r#""\"q\"""#);
+        assert_eq!(expand_with(&[("HASH(x)", "# y x")], "HASH(1)").unwrap(), "# y 1");
+    }
+
+    #[test]
+    fn pasting_joins_tokens_and_rescans() {
+        let cat = [("CAT(a, b)", "a##b"), ("TYPE(x)", "x ## _t"), ("ONE", "1")];
+        assert_eq!(expand_with(&cat, "CAT(counter_, 1)").unwrap(), "counter_1");
+        assert_eq!(expand_with(&cat, "TYPE(size)").unwrap(), "size_t");
+        assert_eq!(expand_with(&cat, "CAT(O, NE)").unwrap(), "1");
+    }
+
+    #[test]
+    fn self_reference_stops() {
+        assert_eq!(expand_with(&[("A", "A")], "A").unwrap(), "A");
+        assert_eq!(expand_with(&[("A", "B"), ("B", "A")], "A B").unwrap(), "A B");
+        assert_eq!(expand_with(&[("F(x)", "F(x + 1)")], "F(0)").unwrap(), "F(0 + 1)");
+    }
+
+    #[test]
+    fn arguments_are_expanded_unless_stringized() {
+        let defines = [("ADD(a, b)", "((a)+(b))"), ("ONE", "1"), ("STR(x)", "#x"), ("XSTR(x)", "STR(x)")];
+        assert_eq!(expand_with(&defines, "ADD(ONE, 2)").unwrap(), "((1)+(2))");
+        assert_eq!(expand_with(&defines, "STR(ONE)").unwrap(), "\"ONE\"");
+        assert_eq!(expand_with(&defines, "XSTR(ONE)").unwrap(), "\"1\"");
+    }
+
+    #[test]
+    fn variadic_arguments() {
+        let log = [
+            ("LOG(fmt, ...)", "printf(fmt, __VA_ARGS__)"),
+            ("ELIDE(fmt, ...)", "printf(fmt, ##__VA_ARGS__)"),
+        ];
+        assert_eq!(expand_with(&log, "LOG(\"%d %d\", 5, 6)").unwrap(), "printf(\"%d %d\", 5, 6)");
+        assert_eq!(expand_with(&log, "ELIDE(\"%d\", 5)").unwrap(), "printf(\"%d\",5)");
+        assert_eq!(expand_with(&log, "ELIDE(\"hi\")").unwrap(), "printf(\"hi\")");
+    }
+
+    #[test]
+    fn nested_calls_expand_fully() {
+        let sq = [("SQ(x)", "((x)*(x))"), ("TWICE(f, x)", "f(f(x))")];
+        assert_eq!(expand_with(&sq, "SQ(SQ(2))").unwrap(), "((((2)*(2)))*(((2)*(2))))");
+        assert_eq!(expand_with(&sq, "TWICE(SQ, 3)").unwrap(), "((((3)*(3)))*(((3)*(3))))");
+    }
+
+    #[test]
+    fn argument_counts_are_checked() {
+        let defines = [("MIN(a, b)", "a"), ("LOG(fmt, ...)", "fmt")];
+        assert!(expand_with(&defines, "MIN(1)").unwrap_err().contains("MIN"));
+        assert!(expand_with(&defines, "MIN(1, 2, 3)").unwrap_err().contains("MIN"));
+        assert!(expand_with(&defines, "LOG()").is_ok());
+        assert_eq!(expand_with(&defines, "LOG(1, 2, 3)").unwrap(), "1");
+    }
+
+    #[test]
+    fn counter_counts_up() {
+        assert_eq!(expand_with(&[], "int a = __COUNTER__, b = __COUNTER__;").unwrap(), "int a = 0, b = 1;");
+    }
+
+    #[test]
+    fn indirect_self_reference_stops() {
+        let defines = [("x", "(4 + y)"), ("y", "(2 * x)"), ("ID(a)", "a")];
+        assert_eq!(expand_with(&defines, "x").unwrap(), "(4 + (2 * x))");
+        assert_eq!(expand_with(&defines, "y").unwrap(), "(2 * (4 + y))");
+        assert_eq!(expand_with(&defines, "ID(x)").unwrap(), "(4 + (2 * x))");
+        assert_eq!(expand_with(&[("foo", "a foo b"), ("ID(a)", "a")], "ID(foo)").unwrap(), "a foo b");
+    }
+
+    #[test]
+    fn calls_can_end_a_line_unclosed() {
+        let macros = table(&[("SQ(x)", "x"), ("N", "1")]);
+        assert!(ends_in_call("int a = SQ(", &macros, false));
+        assert!(ends_in_call("f(SQ(1), SQ(2,", &macros, false));
+        assert!(!ends_in_call("int a = SQ(3) + f(", &macros, false));
+        assert!(!ends_in_call("int a = N(", &macros, false));
+        assert!(!ends_in_call("/* SQ( */", &macros, false));
+        assert!(!ends_in_call("SQ( */", &macros, true));
+    }
+}
@@ -1,123 +1,915 @@
-use regex::Regex;
-use petgraph::graph::{DiGraph, NodeIndex};
-use petgraph::algo::toposort;
-use std::collections::HashMap;
+use c_preprocessor::{
+    decode_text, log, preprocess_named, read_macro_file, read_text, BlankLines, Encoding, HeaderCache,
+    InlineSystem, LineEndings, Options, Output, Standard, Stats, Target, Transformation, Verbosity,
+};
+use rayon::prelude::*;
+use serde_json::json;
+use std::collections::HashSet;
+use std::fmt;
 use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+const USAGE: &str = "\
+Usage: preprocessor <input.c>... [options]
+
+Options:
+  -o, --output <file>   Write to <file> instead of <input>.preprocessed.c (one input only)
+  --out-template <path> Name each output after its input, where {dir}, {name} and {ext} stand
+                        for the input's directory, stem and extension, e.g.
+                        'build/{name}.{ext}'
+  --output-dir <dir>    Write each output to <dir>/<name>.preprocessed.c, creating <dir> if
+                        needed
+  -I <dir>              Search <dir> for headers; may be repeated
+  -isystem <dir>        Search <dir> for headers after the -I directories, as a system
+                        directory; may be repeated
+  --sysroot <dir>       Search <dir>/usr/local/include and <dir>/usr/include after the -I
+                        directories
+  --stdin               Read the source from stdin and print it, the same as the input '-'
+  --base-dir <dir>      Look up the input's own quoted includes in <dir>, e.g. for stdin
+  -D <name>[=<value>]   Define a macro, to 1 if no value is given; may be repeated
+  -U <name>             Undefine a macro, including a predefined one or an earlier -D
+  --define-from-file <file>
+                        Define the macros <file> defines before any -D, without including it
+  --inline-system <never|always|found>
+                        Leave #include <...> for the compiler (default), inline them all, or
+                        inline those found in the -I, -isystem or --sysroot directories
+  --encoding <utf8|latin1>
+                        Fail on files that aren't UTF-8 (default), or read them as Latin-1
+  --include-once-all    Inline each header at most once, even without #pragma once or a guard
+  --max-include-depth <n>
+                        Fail when headers nest more than <n> deep (default 200)
+  --std <c99|c11|c17>   Predefine __STDC__ and the standard's __STDC_VERSION__
+  --no-predefined       Predefine no macros, not even __FILE__ and __LINE__, only those of -D
+  --target <c|rust>     Remove #define lines as cpp does (default), or turn them into Rust consts
+  --strip-comments      Remove comments before the other passes
+  --no-reorder          Keep definitions in their original order
+  --fn-pattern <regex>  Find function definitions with <regex> instead of the built-in pattern;
+                        it must capture the return type, name and parameters, and match
+                        through the body's opening brace
+  --keep-directives     Comment out removed directives and dead branches instead
+  --strip-pragma <prefix>
+                        Remove #pragma lines starting with <prefix>, e.g. 'omp'; may be repeated
+  --line-markers        Emit #line directives pointing back at the original lines
+  --line-endings <lf|crlf|preserve>
+                        End output lines with \\n, \\r\\n or as most source lines are (default)
+  --keep-blank-lines <collapse|preserve>
+                        Collapse runs of blank lines to one, or keep them all (default)
+  --warn-unused-macros  Warn about macros the input defines but never uses
+  --dump-macro-table    Print the macros defined at the end of each input to stderr
+  --stats               Print how much work each input took and how long each pass ran, and
+                        the totals, to stderr
+  --trace-macro <name>  Log each step of every expansion of macro <name>; may be repeated
+  --report <file>       Also write a JSON report of the macros and functions found
+  --emit-deps <file>    Also write a Makefile rule making each output depend on its input and
+                        every header it includes
+  --manifest <file>     Also write a list of the headers inlined, conditionals resolved, macros
+                        expanded and functions moved for each input, for review
+  --emit-dot <file>     Also write the graph definitions are ordered by as Graphviz DOT (one
+                        input only)
+  --stdout              Print the output instead of writing files; messages go to stderr
+  --in-place            Overwrite each input with its output instead
+  --backup              With --in-place, first copy each input to <input>.bak
+  -q, --quiet           Print nothing but errors
+  -v, --verbose         Also log each header included and macro defined; twice to also log
+                        where reordering places each definition";
+
+/// Paths and settings parsed from the command line.
+struct Args {
+    inputs: Vec<String>,
+    output: Option<String>,
+    /// How output paths are made from input paths, if not the default way.
+    out_template: Option<String>,
+    /// The directory every output is written to, if not the one its input is in.
+    output_dir: Option<String>,
+    /// Print the preprocessed code instead of writing it to files.
+    stdout: bool,
+    /// Replace each input with its preprocessed code.
+    in_place: bool,
+    /// Keep a copy of each input replaced in place as `<input>.bak`.
+    backup: bool,
+    /// Print the final macro table of each input to stderr.
+    dump_macro_table: bool,
+    /// Print the statistics of each input, and their totals, to stderr.
+    stats: bool,
+    /// Where to write the JSON report, if anywhere.
+    report: Option<String>,
+    /// Headers whose macros are defined for every input, in order.
+    define_files: Vec<String>,
+    /// Where to write the dependency graph of the single input, if anywhere.
+    emit_dot: Option<String>,
+    /// Where to write the Makefile rules listing the files each output depends on, if anywhere.
+    emit_deps: Option<String>,
+    /// Where to write the list of transformations applied to each input, if anywhere.
+    manifest: Option<String>,
+    options: Options,
+}
 
 fn main() {
-    let input_file = "main.c";
-    let output_file = "preprocessed_main.c";
+    let mut args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            log(&Options::default(), Verbosity::Quiet, format_args!("Error: {}\n{}", e, USAGE));
+            process::exit(2);
+        }
+    };
 
-    match preprocess_main_c(input_file, output_file) {
-        Ok(_) => println!("Preprocessing complete. Output: {}", output_file),
-        Err(e) => eprintln!("Error during preprocessing: {}", e),
+    // Later macro files see the macros of earlier ones, and -D and -U apply on top of them all
+    let mut preloaded = Vec::new();
+    for path in &args.define_files {
+        let macros = preloaded.iter().chain(&args.options.macros).cloned().collect();
+        match read_macro_file(Path::new(path), &Options { macros, ..args.options.clone() }) {
+            Ok(macros) => preloaded.extend(macros),
+            Err(e) => {
+                log(&args.options, Verbosity::Quiet, format_args!("Error reading macros from '{}': {}", path, e));
+                process::exit(1);
+            }
+        }
     }
-}
+    args.options.macros.splice(0..0, preloaded);
 
-/// Preprocesses a single C file by reordering elements and handling macros.
-fn preprocess_main_c(input_file: &str, output_file: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Step 1: Read the file content
-    let original_code = fs::read_to_string(input_file)?;
+    // Headers shared by several inputs are only read once
+    let header_cache = Arc::new(HeaderCache::new());
+    args.options.header_cache = Some(header_cache.clone());
 
-    // Step 2: Inline #include directives (optional, ignored here as we don't have headers in the example)
-    let inlined_code = inline_includes(&original_code)?;
+    let mut outputs: Vec<_> = args
+        .inputs
+        .iter()
+        .map(|input| match &args.output {
+            _ if args.stdout => None,
+            _ if args.in_place => Some(input.clone()),
+            Some(output) => Some(output.clone()),
+            None => Some(default_output(input, args.out_template.as_deref(), args.output_dir.as_deref())),
+        })
+        .collect();
+    // Inputs with the same file name in different directories would otherwise overwrite each
+    // other's output, e.g. with --output-dir
+    if !args.in_place {
+        let mut taken = HashSet::new();
+        for (input, output) in args.inputs.iter().zip(&mut outputs) {
+            let Some(output) = output else { continue };
+            let unique = (1..).map(|n| numbered(output, n)).find(|path| !taken.contains(path)).unwrap();
+            if unique != *output {
+                let message = format_args!(
+                    "Warning: output '{}' is already taken, writing '{}' to '{}'",
+                    output,
+                    input,
+                    unique
+                );
+                log(&args.options, Verbosity::Normal, message);
+            }
+            taken.insert(unique.clone());
+            *output = unique;
+        }
+        if let Err(e) = check_outputs(&args.inputs, &outputs) {
+            log(&args.options, Verbosity::Quiet, format_args!("Error: {}", e));
+            process::exit(2);
+        }
+    }
 
-    // Step 3: Reorder code elements
-    let reordered_code = reorder_elements(&inlined_code)?;
+    // Files are independent, so preprocess them in parallel, but report in input order
+    let results: Vec<_> = args
+        .inputs
+        .par_iter()
+        .zip(outputs)
+        .map(|(input, output)| {
+            let result = preprocess_file(input, output.as_deref(), args.backup, &args.options);
+            (output, result)
+        })
+        .collect();
 
-    // Step 4: Handle macros
-    let final_code = handle_macros(&reordered_code)?;
+    let mut failed = Vec::new();
+    let mut total_warnings = 0;
+    let mut report_entries = Vec::new();
+    let mut deps_rules = String::new();
+    let mut manifest = String::new();
+    let mut total_stats = Stats::default();
+    for (input, (output, result)) in args.inputs.iter().zip(results) {
+        match result {
+            Ok(result) => {
+                total_warnings += result.warnings;
+                if args.stdout {
+                    print!("{}", result.code);
+                }
+                if args.dump_macro_table {
+                    log(&args.options, Verbosity::Quiet, format_args!("{}", dump_macro_table(input, &result)));
+                }
+                if args.stats {
+                    let message = format_args!("Stats for '{}': {}", input, format_stats(&result.stats));
+                    log(&args.options, Verbosity::Quiet, message);
+                    add_stats(&mut total_stats, &result.stats);
+                }
+                if args.report.is_some() {
+                    report_entries.push(report_entry(input, &result));
+                }
+                if args.emit_deps.is_some() {
+                    deps_rules.push_str(&deps_rule(input, output.as_deref(), &result));
+                }
+                if args.manifest.is_some() {
+                    manifest.push_str(&manifest_section(input, &result));
+                }
+                if let (Some(path), Some(graph)) = (&args.emit_dot, &result.dependency_graph) {
+                    if let Err(e) = fs::write(path, graph) {
+                        let message = format_args!("Error writing dependency graph '{}': {}", path, e);
+                        log(&args.options, Verbosity::Quiet, message);
+                        failed.push(input.as_str());
+                        continue;
+                    }
+                }
+                let output = output.unwrap_or_else(|| "<stdout>".into());
+                let message =
+                    format_args!("Preprocessing complete with {} warning(s). Output: {}", result.warnings, output);
+                status(&args, message);
+            }
+            Err(e) => {
+                let message = format_args!("Error during preprocessing of '{}': {}", input, e);
+                log(&args.options, Verbosity::Quiet, message);
+                failed.push(input.as_str());
+            }
+        }
+    }
 
-    // Write the preprocessed code to the output file
-    fs::write(output_file, final_code)?;
+    let message = format_args!("Read {} header file(s) from disk.", header_cache.reads());
+    log(&args.options, Verbosity::Verbose, message);
+    if args.stats && args.inputs.len() > 1 {
+        log(&args.options, Verbosity::Quiet, format_args!("Stats in total: {}", format_stats(&total_stats)));
+    }
+    if args.inputs.len() > 1 {
+        status(&args, format_args!(
+            "Preprocessed {} of {} files successfully, {} warning(s) in total.",
+            args.inputs.len() - failed.len(),
+            args.inputs.len(),
+            total_warnings
+        ));
+        if !failed.is_empty() {
+            log(&args.options, Verbosity::Quiet, format_args!("Failed: {}", failed.join(", ")));
+        }
+    }
 
-    Ok(())
+    if let Some(path) = &args.report {
+        let report = json!({ "version": 1, "files": report_entries });
+        if let Err(e) = fs::write(path, serde_json::to_string_pretty(&report).unwrap() + "\n") {
+            log(&args.options, Verbosity::Quiet, format_args!("Error writing report '{}': {}", path, e));
+            process::exit(1);
+        }
+    }
+
+    if let Some(path) = &args.emit_deps {
+        if let Err(e) = fs::write(path, deps_rules) {
+            log(&args.options, Verbosity::Quiet, format_args!("Error writing dependencies '{}': {}", path, e));
+            process::exit(1);
+        }
+    }
+    if let Some(path) = &args.manifest {
+        if let Err(e) = fs::write(path, manifest) {
+            log(&args.options, Verbosity::Quiet, format_args!("Error writing manifest '{}': {}", path, e));
+            process::exit(1);
+        }
+    }
+
+    if !failed.is_empty() {
+        process::exit(1);
+    }
 }
 
-/// Inlines #include directives by replacing them with the content of the referenced files.
-fn inline_includes(code: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let include_regex = Regex::new(r#"#include\s+"(.+\.h)""#)?;
-    let mut inlined_code = String::new();
+/// Describes the macros and functions found in `input` for the `--report` document, which
+/// holds one of these per successfully preprocessed file. Consumers rely on these field
+/// names, so they must only ever be added to, bumping the document's `version` otherwise.
+fn report_entry(input: &str, output: &Output) -> serde_json::Value {
+    let macros: Vec<_> = output
+        .macros
+        .iter()
+        .map(|(name, definition)| {
+            json!({
+                "name": name,
+                "value": definition.body,
+                "function_like": definition.params.is_some(),
+                "parameters": definition.params.as_deref().unwrap_or_default(),
+                "location": definition.location,
+            })
+        })
+        .collect();
+    let functions: Vec<_> = output
+        .functions
+        .iter()
+        .map(|function| {
+            json!({
+                "name": function.name,
+                "return_type": function.return_type,
+                "parameters": function.params,
+                "file": function.file,
+                "line": function.line,
+            })
+        })
+        .collect();
+    json!({ "input": input, "macros": macros, "functions": functions })
+}
 
-    for line in code.lines() {
-        if let Some(captures) = include_regex.captures(line) {
-            let header_file = captures.get(1).unwrap().as_str();
-            if let Ok(header_content) = fs::read_to_string(header_file) {
-                inlined_code.push_str(&header_content);
-            } else {
-                eprintln!("Warning: Header file '{}' not found. Skipping include.", header_file);
-            }
-        } else {
-            inlined_code.push_str(line);
-            inlined_code.push('\n');
+/// Describes the macros defined at the end of `input` for `--dump-macro-table`, one per line
+/// with its kind, parameters, body and where it was defined.
+fn dump_macro_table(input: &str, output: &Output) -> String {
+    let mut table = format!("Macros defined at the end of '{}':", input);
+    for (name, definition) in &output.macros {
+        let kind = match &definition.params {
+            Some(params) => format!("function-like ({})", params.join(", ")),
+            None => "object-like".into(),
+        };
+        table.push_str(&format!("\n  {} {} '{}' at {}", name, kind, definition.body, definition.location));
+    }
+    table
+}
+
+/// Reports the status line `message` like a warning, but on stdout, unless the code is
+/// printed there and it mustn't mix with it.
+fn status(args: &Args, message: fmt::Arguments) {
+    if args.stdout {
+        log(&args.options, Verbosity::Normal, message);
+    } else if args.options.verbosity >= Verbosity::Normal {
+        println!("{}", message);
+    }
+}
+
+/// Describes `stats` on one line, e.g. `120 line(s), 3 include(s), 40 macro expansion(s),
+/// 5 function(s) reordered; prepare 12.5µs, includes 1.2ms, ...`.
+fn format_stats(stats: &Stats) -> String {
+    let stages: Vec<_> = stats.stages.iter().map(|(stage, time)| format!("{} {:.1?}", stage, time)).collect();
+    format!(
+        "{} line(s), {} include(s), {} macro expansion(s), {} function(s) reordered; {}",
+        stats.lines,
+        stats.includes,
+        stats.expansions,
+        stats.functions_reordered,
+        stages.join(", ")
+    )
+}
+
+/// Adds the counts and stage timings of `stats` to `total`.
+fn add_stats(total: &mut Stats, stats: &Stats) {
+    total.lines += stats.lines;
+    total.includes += stats.includes;
+    total.expansions += stats.expansions;
+    total.functions_reordered += stats.functions_reordered;
+    for &(stage, time) in &stats.stages {
+        match total.stages.iter_mut().find(|(name, _)| *name == stage) {
+            Some((_, total_time)) => *total_time += time,
+            None => total.stages.push((stage, time)),
         }
     }
+}
+
+/// A Makefile rule, as `gcc -M` would write it, making the file `input` was preprocessed
+/// into depend on `input` and every header it included. Output printed to stdout is named
+/// after the input's object file instead.
+fn deps_rule(input: &str, output: Option<&str>, result: &Output) -> String {
+    let target = match output {
+        Some(output) => output.to_string(),
+        None => Path::new(input).with_extension("o").to_string_lossy().into_owned(),
+    };
+    let headers = result.headers.iter().map(|header| header.to_string_lossy());
+    let prerequisites: Vec<_> =
+        std::iter::once(input.into()).chain(headers).map(|path| make_escape(&path)).collect();
+    format!("{}: {}\n", make_escape(&target), prerequisites.join(" "))
+}
 
-    Ok(inlined_code)
+/// The `--manifest` lines for `input`: a heading naming it, then one line per header inlined,
+/// conditional resolved and function moved, in the order they happened, and one per macro
+/// expanded, by name, so that runs over the same code give the same text.
+fn manifest_section(input: &str, result: &Output) -> String {
+    let mut section = format!("{}:\n", input);
+    for transformation in &result.transformations {
+        let line = match transformation {
+            Transformation::Inlined { location, path } => format!("include {} -> {}", location, path.display()),
+            Transformation::Conditional { location, directive, taken } => {
+                format!("conditional {} {} -> {}", location, directive, if *taken { "kept" } else { "dropped" })
+            }
+            Transformation::Reordered { name, from, to } => format!("reorder {} {} -> {}", name, from, to),
+        };
+        section.push_str(&format!("  {}\n", line));
+    }
+    for (name, count) in &result.stats.macro_expansions {
+        section.push_str(&format!("  expand {} x{}\n", name, count));
+    }
+    section
 }
 
-/// Reorders code elements (functions, types) in the file based on dependencies.
-fn reorder_elements(code: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let mut graph = DiGraph::<String, ()>::new();
-    let mut node_map = HashMap::new();
-    let mut functions = Vec::new();
+/// Escapes the spaces, `#` and `$` in `path` so Make reads it as a single file name.
+fn make_escape(path: &str) -> String {
+    path.replace(' ', "\\ ").replace('#', "\\#").replace('$', "$$")
+}
 
-    // Detect function definitions using corrected regex
-    let function_regex = Regex::new(r"(\w+\s+\w+\s*\(.*\)\s*\{)")?;
-    for (idx, line) in code.lines().enumerate() {
-        if let Some(captures) = function_regex.captures(line) {
-            let function_name = format!("Function_{}", idx); // Create unique names for functions
-            let node = graph.add_node(function_name.clone());
-            node_map.insert(function_name.clone(), node);
-            functions.push((function_name, idx));
+/// Preprocesses the file `input`, or stdin if it is `-`, checking first that it exists so a
+/// typo gets a clear message, and writes the result to the file `output` if one is given,
+/// copying `input` byte for byte to `<input>.bak` first if `backup` is set.
+fn preprocess_file(
+    input: &str,
+    output: Option<&str>,
+    backup: bool,
+    opts: &Options,
+) -> Result<Output, Box<dyn std::error::Error + Send + Sync>> {
+    let (source, path) = if input == "-" {
+        let mut bytes = Vec::new();
+        std::io::stdin().read_to_end(&mut bytes)?;
+        (decode_text(bytes, opts.encoding, Path::new("<stdin>"))?, Path::new("<stdin>"))
+    } else if Path::new(input).is_file() {
+        (read_text(Path::new(input), opts.encoding)?, Path::new(input))
+    } else {
+        return Err(format!("input file '{}' does not exist", input).into());
+    };
+    let result = preprocess_named(&source, path, opts)?;
+    if backup {
+        fs::copy(input, format!("{}.bak", input))?;
+    }
+    if let Some(output) = output {
+        if let Some(dir) = Path::new(output).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
         }
+        write_atomically(Path::new(output), &result.code)?;
     }
+    Ok(result)
+}
 
-    // Add mock dependencies for simplicity
-    for i in 0..functions.len() - 1 {
-        let (name_a, _) = &functions[i];
-        let (name_b, _) = &functions[i + 1];
-        if let (Some(&node_a), Some(&node_b)) = (node_map.get(name_a), node_map.get(name_b)) {
-            graph.add_edge(node_a, node_b, ());
+/// Checks that none of `outputs` is one of `inputs`, as e.g. the template `{dir}/{name}.{ext}`
+/// would make it, since only `--in-place` may overwrite an input. Paths are compared once
+/// canonicalized, so `./a.c` is `a.c`.
+fn check_outputs(inputs: &[String], outputs: &[Option<String>]) -> Result<(), String> {
+    let canonical = |path: &str| fs::canonicalize(path).ok();
+    let inputs: Vec<_> = inputs.iter().map(|input| (input, canonical(input))).collect();
+    for output in outputs.iter().flatten() {
+        let Some(path) = canonical(output) else { continue };
+        if let Some((input, _)) = inputs.iter().find(|(_, input)| input.as_ref() == Some(&path)) {
+            return Err(format!("output '{}' is the input '{}'; only '--in-place' overwrites it", output, input));
         }
     }
+    Ok(())
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over `path`, so a
+/// crash part way through never leaves `path` truncated, even when it is the input. A file
+/// already at `path` keeps its permissions.
+fn write_atomically(path: &Path, contents: &str) -> std::io::Result<()> {
+    let name = path.file_name().map_or("out".into(), |name| name.to_string_lossy());
+    let temp = path.with_file_name(format!(".{}.{}.tmp", name, process::id()));
+    fs::write(&temp, contents)?;
+    let replace = || {
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&temp, metadata.permissions())?;
+        }
+        fs::rename(&temp, path)
+    };
+    replace().inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
 
-    // Perform topological sort
-    let sorted_nodes = toposort(&graph, None).map_err(|_| "Cycle detected in dependencies")?;
-    let mut reordered_code = String::new();
+/// Parses the command line described by [`USAGE`]. `-o` and `--emit-dot` are only accepted
+/// together with a single input; `-I`, `-D` and `-U` may be repeated and also accept the
+/// joined `-Idir` form; `-D` and `-U` apply in the order given. With `--in-place`, an input
+/// given more than once is only kept once.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut out_template = None;
+    let mut output_dir = None;
+    let mut stdout = false;
+    let mut dump_macro_table = false;
+    let mut stats = false;
+    let mut in_place = false;
+    let mut backup = false;
+    let mut report = None;
+    let mut emit_dot = None;
+    let mut emit_deps = None;
+    let mut manifest = None;
+    let mut define_files = Vec::new();
+    let mut options = Options::default();
 
-    for node in sorted_nodes {
-        if let Some(function) = functions.iter().find(|(name, _)| *name == graph[node]) {
-            reordered_code.push_str(&format!("// Function start: {}\n", function.0));
-            reordered_code.push_str(&code.lines().nth(function.1).unwrap());
-            reordered_code.push('\n');
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => {
+                let path = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                output = Some(path);
+            }
+            "--out-template" => {
+                let template = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                let mut placeholders = template.split('{').skip(1).map(|rest| rest.split('}').next().unwrap());
+                let unknown = placeholders.find(|name| !["dir", "name", "ext"].contains(name));
+                if let Some(name) = unknown {
+                    return Err(format!("unknown placeholder '{{{}}}' in '{}'", name, template));
+                }
+                out_template = Some(template);
+            }
+            "--output-dir" => {
+                let dir = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                output_dir = Some(dir);
+            }
+            "-I" => {
+                let dir = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.include_dirs.push(PathBuf::from(dir));
+            }
+            "-isystem" => {
+                let dir = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.system_include_dirs.push(PathBuf::from(dir));
+            }
+            _ if arg.starts_with("-isystem") => options.system_include_dirs.push(PathBuf::from(&arg[8..])),
+            "--sysroot" => {
+                let dir = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.sysroot = Some(PathBuf::from(dir));
+            }
+            _ if arg.starts_with("-I") => options.include_dirs.push(PathBuf::from(&arg[2..])),
+            "-D" => {
+                let definition = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.macros.push(parse_define(&definition)?);
+            }
+            _ if arg.starts_with("-D") => options.macros.push(parse_define(&arg[2..])?),
+            "-U" => {
+                let name = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.macros.push((name, None));
+            }
+            _ if arg.starts_with("-U") => options.macros.push((arg[2..].to_string(), None)),
+            "--strip-comments" => options.strip_comments = true,
+            "--include-once-all" => options.include_once_all = true,
+            "--no-reorder" => options.no_reorder = true,
+            "--keep-directives" => options.keep_directives = true,
+            "--line-markers" => options.line_markers = true,
+            "--warn-unused-macros" => options.warn_unused_macros = true,
+            "--stdout" => stdout = true,
+            "--dump-macro-table" => dump_macro_table = true,
+            "--stats" => stats = true,
+            "--in-place" => in_place = true,
+            "--stdin" => inputs.push("-".into()),
+            "--backup" => backup = true,
+            "-q" | "--quiet" => options.verbosity = Verbosity::Quiet,
+            "-v" | "--verbose" => {
+                options.verbosity = match options.verbosity {
+                    Verbosity::Quiet | Verbosity::Normal => Verbosity::Verbose,
+                    _ => Verbosity::Trace,
+                }
+            }
+            "--max-include-depth" => {
+                let depth = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.max_include_depth =
+                    depth.parse().map_err(|_| format!("invalid value '{}' for '{}'", depth, arg))?;
+            }
+            "--line-endings" => {
+                let endings = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.line_endings = match endings.as_str() {
+                    "lf" => LineEndings::Lf,
+                    "crlf" => LineEndings::Crlf,
+                    "preserve" => LineEndings::Preserve,
+                    _ => return Err(format!("invalid value '{}' for '{}'", endings, arg)),
+                };
+            }
+            "--fn-pattern" => {
+                let pattern = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                let regex = regex::Regex::new(&pattern)
+                    .map_err(|error| format!("invalid value '{}' for '{}': {}", pattern, arg, error))?;
+                if regex.captures_len() < 4 {
+                    return Err(format!("invalid value '{}' for '{}': it must have 3 capture groups", pattern, arg));
+                }
+                options.function_pattern = Some(regex);
+            }
+            "--keep-blank-lines" => {
+                let mode = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.blank_lines = match mode.as_str() {
+                    "collapse" => BlankLines::Collapse,
+                    "preserve" => BlankLines::Preserve,
+                    _ => return Err(format!("invalid value '{}' for '{}'", mode, arg)),
+                };
+            }
+            "--strip-pragma" => {
+                let prefix = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.strip_pragmas.push(prefix);
+            }
+            "--target" => {
+                let target = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.target = match target.as_str() {
+                    "c" => Target::C,
+                    "rust" => Target::Rust,
+                    _ => return Err(format!("invalid value '{}' for '{}'", target, arg)),
+                };
+            }
+            "--inline-system" => {
+                let mode = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.inline_system = match mode.as_str() {
+                    "never" => InlineSystem::Never,
+                    "always" => InlineSystem::Always,
+                    "found" => InlineSystem::Found,
+                    _ => return Err(format!("invalid value '{}' for '{}'", mode, arg)),
+                };
+            }
+            "--encoding" => {
+                let encoding = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.encoding = match encoding.as_str() {
+                    "utf8" => Encoding::Utf8,
+                    "latin1" => Encoding::Latin1,
+                    _ => return Err(format!("invalid value '{}' for '{}'", encoding, arg)),
+                };
+            }
+            "--no-predefined" => options.no_predefined = true,
+            "--std" => {
+                let standard = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.standard = Some(match standard.as_str() {
+                    "c99" => Standard::C99,
+                    "c11" => Standard::C11,
+                    "c17" => Standard::C17,
+                    _ => return Err(format!("invalid value '{}' for '{}'", standard, arg)),
+                });
+            }
+            "--base-dir" => {
+                let dir = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.base_dir = Some(PathBuf::from(dir));
+            }
+            "--trace-macro" => {
+                let name = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                options.trace_macros.push(name);
+            }
+            "--define-from-file" => {
+                let path = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                define_files.push(path);
+            }
+            "--report" => {
+                let path = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                report = Some(path);
+            }
+            "--emit-deps" => {
+                let path = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                emit_deps = Some(path);
+            }
+            "--manifest" => {
+                let path = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                manifest = Some(path);
+            }
+            "--emit-dot" => {
+                let path = args.next().ok_or_else(|| format!("missing value for '{}'", arg))?;
+                emit_dot = Some(path);
+                options.dependency_graph = true;
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("unknown option '{}'", arg));
+            }
+            _ => inputs.push(arg),
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err("no input file given".into());
+    }
+    if inputs.iter().any(|input| input == "-") {
+        if inputs.len() > 1 {
+            return Err("stdin cannot be read along with other input files".into());
         }
+        if in_place {
+            return Err("'--in-place' cannot be used with stdin".into());
+        }
+        stdout |= output.is_none();
+    }
+    if output.is_some() && inputs.len() > 1 {
+        return Err("'-o' cannot be used with multiple input files".into());
+    }
+    if emit_dot.is_some() && inputs.len() > 1 {
+        return Err("'--emit-dot' cannot be used with multiple input files".into());
+    }
+    if output.is_some() && out_template.is_some() {
+        return Err("'-o' cannot be used with '--out-template'".into());
+    }
+    if output_dir.is_some() && (output.is_some() || out_template.is_some()) {
+        return Err("'--output-dir' cannot be used with '-o' or '--out-template'".into());
+    }
+    if output_dir.is_some() && (stdout || in_place) {
+        return Err("'--output-dir' cannot be used with '--stdout' or '--in-place'".into());
+    }
+    if output.is_some() && stdout {
+        return Err("'-o' cannot be used with '--stdout'".into());
+    }
+    if in_place && output.is_some() {
+        return Err("'--in-place' cannot be used with '-o'".into());
+    }
+    if in_place && stdout {
+        return Err("'--in-place' cannot be used with '--stdout'".into());
+    }
+    if in_place && out_template.is_some() {
+        return Err("'--in-place' cannot be used with '--out-template'".into());
+    }
+    if backup && !in_place {
+        return Err("'--backup' can only be used with '--in-place'".into());
+    }
+    // Rewriting a file in place from two jobs at once would race on its temporary file
+    if in_place {
+        let mut seen = HashSet::new();
+        inputs.retain(|input| seen.insert(fs::canonicalize(input).unwrap_or_else(|_| PathBuf::from(input))));
+    }
+    Ok(Args {
+        inputs,
+        output,
+        out_template,
+        output_dir,
+        stdout,
+        in_place,
+        backup,
+        dump_macro_table,
+        stats,
+        report,
+        define_files,
+        emit_dot,
+        emit_deps,
+        manifest,
+        options,
+    })
+}
+
+/// Splits a `-D` value into a macro name and value; a bare `NAME` is defined to `1`.
+fn parse_define(definition: &str) -> Result<(String, Option<String>), String> {
+    let (name, value) = definition.split_once('=').unwrap_or((definition, "1"));
+    if !name.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+        return Err(format!("invalid macro name in '-D {}'", definition));
     }
+    Ok((name.to_string(), Some(value.to_string())))
+}
 
-    Ok(reordered_code)
+/// Returns the default output path for `input`: `<stem>.preprocessed.c` next to the input,
+/// or in `dir` if one is given, unless `template` names it instead, with `{dir}`, `{name}`
+/// and `{ext}` replaced by the input's directory (`.` if it has none), stem and extension.
+fn default_output(input: &str, template: Option<&str>, dir: Option<&str>) -> String {
+    let path = Path::new(input);
+    let stem = path.file_stem().map_or("out".into(), |s| s.to_string_lossy());
+    if let Some(template) = template {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let ext = path.extension().map_or("".into(), |ext| ext.to_string_lossy());
+        return template.replace("{dir}", &dir.to_string_lossy()).replace("{name}", &stem).replace("{ext}", &ext);
+    }
+    let name = format!("{}.preprocessed.c", stem);
+    match dir {
+        Some(dir) => Path::new(dir).join(name),
+        None => path.with_file_name(name),
+    }
+    .to_string_lossy()
+    .into_owned()
 }
 
-/// Handles macros by converting them into Rust-compatible constructs.
-fn handle_macros(code: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let macro_regex = Regex::new(r#"#define\s+(\w+)\s*(.*)"#)?;
-    let mut processed_code = String::new();
+/// Returns `path` with `.<n>` added before its extension, e.g. `out.2.c`, or `path` itself
+/// for `n` = 1.
+fn numbered(path: &str, n: usize) -> String {
+    if n == 1 {
+        return path.to_string();
+    }
+    let path = Path::new(path);
+    let stem = path.file_stem().map_or("out".into(), |s| s.to_string_lossy());
+    let name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}.{}", stem, n),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
 
-    for line in code.lines() {
-        if let Some(captures) = macro_regex.captures(line) {
-            let macro_name = captures.get(1).unwrap().as_str();
-            let macro_value = captures.get(2).map_or("", |m| m.as_str());
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // Convert macros into Rust constants or cfg attributes
-            if macro_value.is_empty() {
-                processed_code.push_str(&format!("#[cfg({})]\n", macro_name));
-            } else {
-                processed_code.push_str(&format!("const {}: &str = \"{}\";\n", macro_name, macro_value));
-            }
-        } else {
-            processed_code.push_str(line);
-            processed_code.push('\n');
+    /// Parses `args`, as given after the program name.
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// A fresh, empty directory for the test `name` under the system's temporary directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("c_preprocessor-main-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn in_place_rewrites_the_input_and_keeps_a_backup() {
+        let input = temp_dir("in-place").join("main.c");
+        let source = "int a(void) { return b(); }\nint b(void) { return 1; }\n";
+        fs::write(&input, source).unwrap();
+        let input = input.to_str().unwrap();
+        let args = parse(&[input, "--in-place", "--backup", input]).unwrap();
+        assert_eq!(args.inputs, [input]);
+
+        let result = preprocess_file(input, Some(input), args.backup, &args.options).unwrap();
+        assert_eq!(fs::read_to_string(format!("{}.bak", input)).unwrap(), source);
+        assert_eq!(fs::read_to_string(input).unwrap(), result.code);
+        assert!(result.code.find("int b(void)") < result.code.find("int a(void)"));
+    }
+
+    #[test]
+    fn command_line_defines_feed_conditionals() {
+        let args = parse(&["m.c", "-D", "DEBUG", "-DVERSION=3"]).unwrap();
+        let defined = [("DEBUG".into(), Some("1".into())), ("VERSION".into(), Some("3".into()))];
+        assert_eq!(args.options.macros, defined);
+        let source = "#ifdef DEBUG\nint v = VERSION;\n#endif\n";
+        let code = preprocess_named(source, Path::new("m.c"), &args.options).unwrap().code;
+        assert_eq!(code, "int v = 3;\n");
+    }
+
+    #[test]
+    fn command_line_undefines_apply_in_order() {
+        let args = parse(&["m.c", "-D", "FOO", "-U", "FOO", "-U__LINE__"]).unwrap();
+        let source = "#ifdef FOO\nint foo;\n#endif\nint line = __LINE__;\n";
+        let code = preprocess_named(source, Path::new("m.c"), &args.options).unwrap().code;
+        assert_eq!(code, "int line = __LINE__;\n");
+    }
+
+    #[test]
+    fn deps_rules_list_every_header() {
+        let dir = temp_dir("deps");
+        fs::write(dir.join("a.h"), "#include \"b.h\"\n").unwrap();
+        fs::write(dir.join("b.h"), "int b;\n").unwrap();
+        let input = dir.join("main.c");
+        fs::write(&input, "#include \"a.h\"\n").unwrap();
+        let input = input.to_str().unwrap();
+        let result = preprocess_file(input, None, false, &Options::default()).unwrap();
+        let (a, b) = (dir.join("a.h"), dir.join("b.h"));
+        let expected = format!("out.c: {} {} {}\n", input, a.display(), b.display());
+        assert_eq!(deps_rule(input, Some("out.c"), &result), expected);
+    }
+
+    #[test]
+    fn output_templates_name_outputs_after_inputs() {
+        assert_eq!(default_output("src/foo.c", Some("{dir}/pp_{name}.{ext}"), None), "src/pp_foo.c");
+        assert_eq!(default_output("foo.c", Some("build/{name}.i"), None), "build/foo.i");
+        assert_eq!(default_output("foo.c", Some("{dir}/{name}"), None), "./foo");
+    }
+
+    #[test]
+    fn macro_table_dump_shows_final_definitions() {
+        let opts = parse(&["m.c", "-DLEVEL=2"]).unwrap().options;
+        let source = "#define PI 3\n#define PI 4\n#define MAX(a, b) a\n#define GONE\n#undef GONE\n";
+        let result = preprocess_named(source, Path::new("m.c"), &opts).unwrap();
+        let table = dump_macro_table("m.c", &result);
+        assert!(table.starts_with("Macros defined at the end of 'm.c':\n"));
+        assert!(table.contains("\n  PI object-like '4' at m.c:2"));
+        assert!(table.contains("\n  MAX function-like (a, b) 'a' at m.c:3"));
+        assert!(table.contains("\n  LEVEL object-like '2' at <command line>"));
+        assert!(!table.contains("GONE") && !table.contains("__LINE__"));
+    }
+
+    #[test]
+    fn output_dir_collects_every_output() {
+        let dir = temp_dir("output-dir");
+        for name in ["a.c", "b.c"] {
+            fs::write(dir.join(name), "int x;\n").unwrap();
         }
+        let out = dir.join("build");
+        let out = out.to_str().unwrap();
+        let (a, b) = (dir.join("a.c"), dir.join("b.c"));
+        let args = parse(&[a.to_str().unwrap(), b.to_str().unwrap(), "--output-dir", out]).unwrap();
+        for input in &args.inputs {
+            let output = default_output(input, None, args.output_dir.as_deref());
+            preprocess_file(input, Some(&output), false, &args.options).unwrap();
+        }
+        assert!(Path::new(out).join("a.preprocessed.c").is_file());
+        assert!(Path::new(out).join("b.preprocessed.c").is_file());
+        assert_eq!(numbered("build/a.preprocessed.c", 2), "build/a.preprocessed.2.c");
+    }
+
+    #[test]
+    fn manifests_list_includes_and_conditionals() {
+        let dir = temp_dir("manifest");
+        fs::write(dir.join("h.h"), "#define SQ(x) ((x)*(x))\n").unwrap();
+        let input = dir.join("main.c");
+        fs::write(&input, "#include \"h.h\"\n#ifdef DEBUG\nint d;\n#endif\nint s = SQ(2);\n").unwrap();
+        let input = input.to_str().unwrap();
+        let result = preprocess_file(input, None, false, &Options::default()).unwrap();
+        let expected = format!(
+            "{input}:\n  include {input}:1 -> {}\n  conditional {input}:2 #ifdef DEBUG -> dropped\n\
+             \x20 expand SQ x1\n",
+            dir.join("h.h").display()
+        );
+        assert_eq!(manifest_section(input, &result), expected);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn in_place_backups_keep_the_original_bytes_and_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let input = temp_dir("backup").join("latin1.c");
+        fs::write(&input, b"char c = '\xe9';\n").unwrap();
+        fs::set_permissions(&input, fs::Permissions::from_mode(0o750)).unwrap();
+        let input = input.to_str().unwrap();
+        let args = parse(&[input, "--in-place", "--backup", "--encoding", "latin1"]).unwrap();
+        preprocess_file(input, Some(input), args.backup, &args.options).unwrap();
+        assert_eq!(fs::read(format!("{}.bak", input)).unwrap(), b"char c = '\xe9';\n");
+        assert_eq!(fs::metadata(input).unwrap().permissions().mode() & 0o777, 0o750);
+    }
+
+    #[test]
+    fn in_place_rejects_an_out_template() {
+        let error = parse(&["m.c", "--in-place", "--out-template", "{name}.i"]).err().unwrap();
+        assert_eq!(error, "'--in-place' cannot be used with '--out-template'");
     }
 
-    Ok(processed_code)
+    #[test]
+    fn outputs_never_overwrite_inputs() {
+        let input = temp_dir("overwrite").join("main.c");
+        fs::write(&input, "int x;\n").unwrap();
+        let inputs = [input.to_str().unwrap().to_string()];
+        let output = default_output(&inputs[0], Some("{dir}/{name}.{ext}"), None);
+        let error = check_outputs(&inputs, &[Some(output)]).err().unwrap();
+        assert!(error.ends_with("only '--in-place' overwrites it"), "{}", error);
+        let output = default_output(&inputs[0], Some("{dir}/{name}.i"), None);
+        assert_eq!(check_outputs(&inputs, &[Some(output), None]), Ok(()));
+    }
 }
@@ -0,0 +1,177 @@
+//! Splitting lines of C source into tokens, so that nothing inside a comment or a string or
+//! character literal is ever mistaken for code.
+
+/// What a [`Token`] is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// An identifier or keyword.
+    Ident,
+    /// A number, including any letters, digits, `_` and `.` that follow its first digit, so
+    /// the `FF` of `0xFF` and the `e10` of `1e10` aren't read as identifiers.
+    Number,
+    /// A string literal, through its closing quote or, if it has none, the end of the line.
+    String,
+    /// A character constant, through its closing quote or, if it has none, the end of the line.
+    Char,
+    /// A `//` comment, or as much of a `/* ... */` comment as is on the line.
+    Comment,
+    /// The `#` introducing a directive: the first token of a line that isn't in a comment.
+    Directive,
+    /// A run of whitespace.
+    Whitespace,
+    /// Any other character, such as an operator or a brace.
+    Punct,
+}
+
+/// A token and its text, which is a slice of the line it was read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: Kind,
+    pub text: &'a str,
+}
+
+/// The tokens of a line, in order, returned by [`tokenize`].
+pub struct Tokens<'a, 'b> {
+    rest: &'a str,
+    in_comment: &'b mut bool,
+    /// Whether only whitespace has been read so far, so a `#` would start a directive.
+    line_start: bool,
+}
+
+/// Splits `line` into tokens. `in_comment` says whether a block comment is open at the start
+/// of the line and is updated as the tokens are read, so it is right for the next line once
+/// they all have been.
+pub fn tokenize<'a, 'b>(line: &'a str, in_comment: &'b mut bool) -> Tokens<'a, 'b> {
+    Tokens { rest: line, in_comment, line_start: true }
+}
+
+impl<'a> Iterator for Tokens<'a, '_> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let mut token = next_token(self.rest, self.in_comment);
+        if token.kind == Kind::Punct && token.text == "#" && self.line_start {
+            token.kind = Kind::Directive;
+        }
+        self.line_start &= token.kind == Kind::Whitespace;
+        self.rest = &self.rest[token.text.len()..];
+        Some(token)
+    }
+}
+
+/// Reads the token at the start of `text`, which must not be empty. `in_comment` says
+/// whether a block comment is open at the start of `text` and is updated to say whether one
+/// is open after the token. A `#` is always a [`Kind::Punct`] here, as only [`tokenize`]
+/// knows whether it starts its line.
+pub fn next_token<'a>(text: &'a str, in_comment: &mut bool) -> Token<'a> {
+    let token = |kind, len| Token { kind, text: &text[..len] };
+    if *in_comment {
+        let len = match text.find("*/") {
+            Some(end) => {
+                *in_comment = false;
+                end + 2
+            }
+            None => text.len(),
+        };
+        return token(Kind::Comment, len);
+    }
+
+    let c = text.chars().next().unwrap();
+    if text.starts_with("//") {
+        token(Kind::Comment, text.len())
+    } else if let Some(rest) = text.strip_prefix("/*") {
+        *in_comment = true;
+        let len = if rest.is_empty() { 0 } else { next_token(rest, in_comment).text.len() };
+        token(Kind::Comment, 2 + len)
+    } else if c == '"' {
+        token(Kind::String, literal_len(text, c).unwrap_or(text.len()))
+    } else if c == '\'' {
+        token(Kind::Char, literal_len(text, c).unwrap_or(text.len()))
+    } else if c.is_ascii_digit() {
+        token(Kind::Number, text.find(|c: char| !c.is_alphanumeric() && c != '_' && c != '.').unwrap_or(text.len()))
+    } else if c.is_alphabetic() || c == '_' {
+        token(Kind::Ident, text.find(|c: char| !c.is_alphanumeric() && c != '_').unwrap_or(text.len()))
+    } else if c.is_whitespace() {
+        token(Kind::Whitespace, text.len() - text.trim_start().len())
+    } else {
+        token(Kind::Punct, c.len_utf8())
+    }
+}
+
+/// Length of the string or character literal opening `text` with `quote`, including both
+/// quotes, or `None` if it isn't closed on the line. A backslash escapes the character after
+/// it, so `'\''` and `"a\"b"` are each one literal.
+pub fn literal_len(text: &str, quote: char) -> Option<usize> {
+    let mut chars = text.char_indices().skip(1);
+    while let Some((idx, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c == quote {
+            return Some(idx + 1);
+        }
+    }
+    None
+}
+
+/// The name of the directive on `line`, such as `include` for `#include "a.h"`, or `None`
+/// if the line isn't one, e.g. because it is part of a block comment. A null directive,
+/// a `#` alone, has the empty name. `in_comment` is updated as by [`tokenize`], as the whole
+/// line is read either way.
+pub fn directive<'a>(line: &'a str, in_comment: &mut bool) -> Option<&'a str> {
+    let mut tokens = tokenize(line, in_comment);
+    let first = tokens.find(|token| token.kind != Kind::Whitespace);
+    let is_directive = first.is_some_and(|token| token.kind == Kind::Directive);
+    let name = tokens
+        .by_ref()
+        .find(|token| !matches!(token.kind, Kind::Whitespace | Kind::Comment))
+        .filter(|token| token.kind == Kind::Ident)
+        .map_or("", |token| token.text);
+    tokens.for_each(drop);
+    is_directive.then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The kind and text of each token of `line`, which starts outside any comment.
+    fn tokens(line: &str) -> Vec<(Kind, &str)> {
+        tokenize(line, &mut false).map(|token| (token.kind, token.text)).collect()
+    }
+
+    #[test]
+    fn escaped_quotes_stay_in_their_literal() {
+        assert_eq!(tokens(r"c = '\'';"), [
+            (Kind::Ident, "c"),
+            (Kind::Whitespace, " "),
+            (Kind::Punct, "="),
+            (Kind::Whitespace, " "),
+            (Kind::Char, r"'\''"),
+            (Kind::Punct, ";"),
+        ]);
+        let string = [(Kind::String, r#""a\"b""#), (Kind::Whitespace, " "), (Kind::Ident, "x")];
+        assert_eq!(tokens(r#""a\"b" x"#), string);
+    }
+
+    #[test]
+    fn comments_split_tokens() {
+        assert_eq!(tokens("a/* c */b"), [(Kind::Ident, "a"), (Kind::Comment, "/* c */"), (Kind::Ident, "b")]);
+        let mut in_comment = false;
+        assert_eq!(tokenize("x /* open", &mut in_comment).last().map(|token| token.kind), Some(Kind::Comment));
+        assert!(in_comment);
+        assert_eq!(directive("#define X */", &mut in_comment), None);
+        assert!(!in_comment);
+    }
+
+    #[test]
+    fn directives_start_lines() {
+        assert_eq!(directive("  #  include <x.h>", &mut false), Some("include"));
+        assert_eq!(directive("#", &mut false), Some(""));
+        assert_eq!(directive("x = a#b;", &mut false), None);
+        assert_eq!(tokens("0x1F+1e10")[0], (Kind::Number, "0x1F"));
+        assert_eq!(literal_len(r#""abc"#, '"'), None);
+    }
+}